@@ -0,0 +1,367 @@
+use crate::Kti;
+use crate::detect::{get_correct_extension, read_at, zip_entries};
+use flate2::read::{DeflateDecoder, GzDecoder};
+use serde::Serialize;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// Only the start of each member is detected, and the scan stops after this
+// many members or bytes read, so a huge archive or a zip bomb can't keep kti
+// busy.
+const MEMBER_PEEK: u64 = 4096;
+const MAX_MEMBERS: usize = 64;
+const MAX_BYTES_READ: u64 = 16 * 1024 * 1024;
+
+// How many names in the temp directory are tried for the member sample, in
+// case earlier runs left some behind.
+const SAMPLE_ATTEMPTS: usize = 100;
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+/// A file inside an archive and the type its first bytes were detected as.
+#[derive(Debug, Serialize)]
+pub struct Member {
+    pub name: String,
+    pub detected: Option<String>,
+}
+
+/// The members --scan-archives found in an archive.
+#[derive(Debug, Default, Serialize)]
+pub struct Contents {
+    pub members: Vec<Member>,
+    /// Set when the scan stopped before the end of the archive.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub incomplete: bool,
+}
+
+/// Lists the files in a zip, tar or gzip file with the type of each, for
+/// --scan-archives. Nothing is extracted, each member's start is written to
+/// a temporary file for detection, which is removed again. Other files give
+/// `None`.
+pub fn scan_archive(
+    path: &Path,
+    extension: &str,
+    kti: &Kti,
+) -> Result<Option<Contents>, Box<dyn Error>> {
+    if !matches!(extension, "zip" | "tar" | "gz") {
+        return Ok(None);
+    }
+    let mut scanner = Scanner {
+        sample: Sample::create()?,
+        kti,
+        contents: Contents::default(),
+    };
+    match extension {
+        "zip" => scanner.scan_zip(path)?,
+        "tar" => scanner.scan_tar(&mut Budget::new(fs::File::open(path)?))?,
+        _ => scanner.scan_gzip(path)?,
+    }
+    Ok(Some(scanner.contents))
+}
+
+/// The temporary file members are detected from, as detection reads files by
+/// path to look past their start. It is created under a name that wasn't
+/// taken, so kti never writes through a link someone else put in the temp
+/// directory, and kept open until the scan is done.
+struct Sample {
+    path: PathBuf,
+    file: fs::File,
+}
+
+impl Sample {
+    fn create() -> io::Result<Sample> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut last_error = None;
+        for attempt in 0..SAMPLE_ATTEMPTS {
+            let path =
+                std::env::temp_dir().join(format!("kti-member-{}-{attempt}", std::process::id()));
+            match options.open(&path) {
+                Ok(file) => return Ok(Sample { path, file }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => last_error = Some(e),
+                Err(e) => return Err(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| io::ErrorKind::AlreadyExists.into()))
+    }
+
+    /// Replaces the contents of the sample with `head`.
+    fn write(&mut self, head: &[u8]) -> io::Result<&Path> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(head)?;
+        Ok(&self.path)
+    }
+}
+
+impl Drop for Sample {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+struct Scanner<'a> {
+    sample: Sample,
+    kti: &'a Kti,
+    contents: Contents,
+}
+
+impl Scanner<'_> {
+    /// Detects a member from its first bytes. Returns false once no more
+    /// members should be added.
+    fn add(&mut self, name: String, head: &[u8]) -> Result<bool, Box<dyn Error>> {
+        if self.contents.members.len() == MAX_MEMBERS {
+            self.contents.incomplete = true;
+            return Ok(false);
+        }
+        let sample = self.sample.write(head)?;
+        let detected =
+            get_correct_extension(sample, self.kti)?.map(|detection| detection.extension);
+        self.contents.members.push(Member { name, detected });
+        Ok(true)
+    }
+
+    fn scan_zip(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let mut file = fs::File::open(path)?;
+        for entry in zip_entries(&mut file)? {
+            if entry.name.ends_with(b"/") {
+                continue;
+            }
+            let header = read_at(&mut file, entry.header_offset, 30)?;
+            let Some(&[n1, n2, e1, e2]) = header.get(26..30) else {
+                continue;
+            };
+            let data_offset = entry.header_offset
+                + 30
+                + u16::from_le_bytes([n1, n2]) as u64
+                + u16::from_le_bytes([e1, e2]) as u64;
+            let compressed = read_at(
+                &mut file,
+                data_offset,
+                entry.compressed_size.min(MEMBER_PEEK),
+            )?;
+            let head = match entry.method {
+                STORED => compressed,
+                // The compressed start of a member is enough to inflate its
+                // first bytes, the rest of the stream is never looked at.
+                DEFLATED => {
+                    let mut head = Vec::new();
+                    let _ = DeflateDecoder::new(&compressed[..])
+                        .take(MEMBER_PEEK)
+                        .read_to_end(&mut head);
+                    head
+                }
+                _ => Vec::new(),
+            };
+            let name = String::from_utf8_lossy(&entry.name).into_owned();
+            if !self.add(name, &head)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn scan_tar<R: Read>(&mut self, reader: &mut Budget<R>) -> Result<(), Box<dyn Error>> {
+        loop {
+            let mut header = [0; 512];
+            if !reader.read_block(&mut header)? {
+                self.contents.incomplete |= reader.exhausted();
+                return Ok(());
+            }
+            // Two zero blocks end the archive, one is enough to stop at.
+            if header.iter().all(|byte| *byte == 0) {
+                return Ok(());
+            }
+            let name_end = header[..100].iter().position(|byte| *byte == 0);
+            let name = String::from_utf8_lossy(&header[..name_end.unwrap_or(100)]).into_owned();
+            let Some(size) = parse_octal(&header[124..136]) else {
+                return Ok(());
+            };
+            let head_len = if matches!(header[156], b'0' | 0) {
+                size.min(MEMBER_PEEK)
+            } else {
+                // Directories, links and metadata entries aren't files.
+                0
+            };
+            let mut head = vec![0; head_len as usize];
+            if !reader.read_block(&mut head)? {
+                self.contents.incomplete |= reader.exhausted();
+                return Ok(());
+            }
+            if head_len > 0 && !self.add(name, &head)? {
+                return Ok(());
+            }
+            // Member data is padded to whole blocks.
+            let padded = size.div_ceil(512) * 512;
+            if !reader.skip(padded - head_len)? {
+                self.contents.incomplete |= reader.exhausted();
+                return Ok(());
+            }
+        }
+    }
+
+    fn scan_gzip(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let mut decoder = GzDecoder::new(fs::File::open(path)?);
+        let mut head = Vec::new();
+        (&mut decoder).take(512).read_to_end(&mut head)?;
+        // Compressed tarballs list the files of the tar, anything else is a
+        // single member named like the gzip header says.
+        if head.get(257..262) == Some(b"ustar") {
+            let mut reader = Budget::new(head.as_slice().chain(decoder));
+            return self.scan_tar(&mut reader);
+        }
+        let name = match decoder.header().and_then(|header| header.filename()) {
+            Some(name) => String::from_utf8_lossy(name).into_owned(),
+            None => path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+        };
+        (&mut decoder)
+            .take(MEMBER_PEEK - head.len() as u64)
+            .read_to_end(&mut head)?;
+        self.add(name, &head)?;
+        Ok(())
+    }
+}
+
+/// A reader that stops once MAX_BYTES_READ bytes were read through it.
+struct Budget<R> {
+    reader: R,
+    read: u64,
+}
+
+impl<R: Read> Budget<R> {
+    fn new(reader: R) -> Budget<R> {
+        Budget { reader, read: 0 }
+    }
+
+    fn exhausted(&self) -> bool {
+        self.read >= MAX_BYTES_READ
+    }
+
+    /// Fills `buf`, or returns false if the archive or the budget ends first.
+    fn read_block(&mut self, buf: &mut [u8]) -> io::Result<bool> {
+        if self.read + buf.len() as u64 > MAX_BYTES_READ {
+            self.read = MAX_BYTES_READ;
+            return Ok(false);
+        }
+        match self.reader.read_exact(buf) {
+            Ok(()) => {
+                self.read += buf.len() as u64;
+                Ok(true)
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn skip(&mut self, len: u64) -> io::Result<bool> {
+        if self.read + len > MAX_BYTES_READ {
+            self.read = MAX_BYTES_READ;
+            return Ok(false);
+        }
+        let skipped = io::copy(&mut (&mut self.reader).take(len), &mut io::sink())?;
+        self.read += skipped;
+        Ok(skipped == len)
+    }
+}
+
+// Tar sizes are octal digits padded with spaces or NULs.
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let digits: String = field
+        .iter()
+        .map(|byte| *byte as char)
+        .filter(|c| !c.is_whitespace() && *c != '\0')
+        .collect();
+    u64::from_str_radix(&digits, 8).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{TempDir, kti, tar_archive, zip_archive};
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR";
+    const PDF: &[u8] = b"%PDF-1.7\n%\xE2\xE3\xCF\xD3\n";
+
+    fn scan(name: &str, contents: &[u8], extension: &str) -> Contents {
+        let dir = TempDir::new();
+        let path = dir.file(name, contents);
+        scan_archive(&path, extension, &kti(&[])).unwrap().unwrap()
+    }
+
+    fn members(contents: &Contents) -> Vec<(&str, Option<&str>)> {
+        contents
+            .members
+            .iter()
+            .map(|member| (member.name.as_str(), member.detected.as_deref()))
+            .collect()
+    }
+
+    #[test]
+    fn zip_members_are_detected() {
+        let archive = zip_archive(&[("img/a.dat", PNG), ("img/", b""), ("b.txt", PDF)]);
+        let contents = scan("a.zip", &archive, "zip");
+        assert_eq!(
+            members(&contents),
+            [("img/a.dat", Some("png")), ("b.txt", Some("pdf"))]
+        );
+        assert!(!contents.incomplete);
+    }
+
+    #[test]
+    fn tar_members_are_detected() {
+        let archive = tar_archive(&[("a.dat", PNG), ("b.txt", PDF), ("c", b"\0\x01")]);
+        let contents = scan("a.tar", &archive, "tar");
+        assert_eq!(
+            members(&contents),
+            [("a.dat", Some("png")), ("b.txt", Some("pdf")), ("c", None)]
+        );
+    }
+
+    #[test]
+    fn scans_stop_after_the_member_limit() {
+        let names: Vec<String> = (0..MAX_MEMBERS + 1).map(|n| format!("{n}.png")).collect();
+        let members: Vec<(&str, &[u8])> = names.iter().map(|name| (name.as_str(), PNG)).collect();
+        let contents = scan("a.tar", &tar_archive(&members), "tar");
+        assert_eq!(contents.members.len(), MAX_MEMBERS);
+        assert!(contents.incomplete);
+    }
+
+    #[test]
+    fn other_files_are_not_scanned() {
+        let dir = TempDir::new();
+        let path = dir.file("a.png", PNG);
+        assert!(scan_archive(&path, "png", &kti(&[])).unwrap().is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn samples_never_write_through_links() {
+        let dir = TempDir::new();
+        let target = dir.file("target", b"keep");
+        let mut links = Vec::new();
+        for attempt in 0..3 {
+            let link =
+                std::env::temp_dir().join(format!("kti-member-{}-{attempt}", std::process::id()));
+            if std::os::unix::fs::symlink(&target, &link).is_ok() {
+                links.push(link);
+            }
+        }
+        let archive = zip_archive(&[("a.png", PNG)]);
+        let contents = scan("a.zip", &archive, "zip");
+        for link in links {
+            fs::remove_file(link).unwrap();
+        }
+        assert_eq!(fs::read(&target).unwrap(), b"keep");
+        assert_eq!(members(&contents), [("a.png", Some("png"))]);
+    }
+}
@@ -0,0 +1,56 @@
+use std::path::Path;
+
+// Directory extensions of macOS and iOS bundles and what kind of bundle each
+// is. Matched case insensitively, as on the default macOS file systems.
+const BUNDLE_TYPES: [(&str, &str); 11] = [
+    ("app", "application"),
+    ("appex", "app extension"),
+    ("bundle", "loadable bundle"),
+    ("framework", "framework"),
+    ("kext", "kernel extension"),
+    ("mdimporter", "Spotlight importer"),
+    ("plugin", "plug-in"),
+    ("prefpane", "preference pane"),
+    ("qlgenerator", "Quick Look generator"),
+    ("saver", "screen saver"),
+    ("xpc", "XPC service"),
+];
+
+// Where bundles keep their Info.plist: under Contents on macOS, at the top on
+// iOS, and under Resources in macOS frameworks.
+const INFO_PLISTS: [&str; 3] = ["Contents/Info.plist", "Info.plist", "Resources/Info.plist"];
+
+/// The kind of bundle `dir` is, for --detect-bundles. A directory counts as
+/// a bundle when it has a bundle extension and an Info.plist where that kind
+/// of bundle keeps it, so a folder that's merely named like one isn't.
+pub fn bundle_type(dir: &Path) -> Option<&'static str> {
+    let extension = dir.extension()?.to_str()?.to_lowercase();
+    let (_, kind) = BUNDLE_TYPES.iter().find(|(ext, _)| *ext == extension)?;
+    INFO_PLISTS
+        .iter()
+        .any(|plist| dir.join(plist).is_file())
+        .then_some(*kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn bundles_need_their_info_plist() {
+        let dir = TempDir::new();
+        dir.file("Viewer.app/Contents/Info.plist", b"<plist/>");
+        dir.file("Phone.APP/Info.plist", b"<plist/>");
+        dir.file("Kit.framework/Resources/Info.plist", b"<plist/>");
+        dir.file("Empty.app/Contents/MacOS/viewer", b"");
+        dir.file("notes/Info.plist", b"<plist/>");
+
+        let root = dir.path();
+        assert_eq!(bundle_type(&root.join("Viewer.app")), Some("application"));
+        assert_eq!(bundle_type(&root.join("Phone.APP")), Some("application"));
+        assert_eq!(bundle_type(&root.join("Kit.framework")), Some("framework"));
+        assert_eq!(bundle_type(&root.join("Empty.app")), None);
+        assert_eq!(bundle_type(&root.join("notes")), None);
+    }
+}
@@ -0,0 +1,199 @@
+use crate::Kti;
+use crate::detect::{Detection, get_correct_extension};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+// Only the start of a file is fingerprinted, which is where nearly all
+// signatures are. Together with the size this is enough to tell a touched
+// file from a changed one without reading all of it.
+const FINGERPRINT_LEN: u64 = 4096;
+
+/// Detections of earlier runs, kept in the --cache file. A file whose size and
+/// first bytes are unchanged is not detected again, even if its modification
+/// time changed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Cache {
+    /// The detection flags the entries were made with. A cache written with
+    /// other flags could hold different results, so it is not used.
+    options: String,
+    entries: HashMap<PathBuf, Entry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    size: u64,
+    fingerprint: u64,
+    detection: Option<Detection>,
+}
+
+impl Cache {
+    /// An empty cache for the detection flags in `kti`.
+    pub fn new(kti: &Kti) -> Cache {
+        Cache {
+            options: detection_options(kti),
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Loads the cache at `path`, or starts an empty one if it doesn't exist
+    /// yet or was made with other detection flags.
+    pub fn load(path: &Path, kti: &Kti) -> Result<Cache, Box<dyn Error>> {
+        let cache: Cache = match fs::read(path) {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Cache::new(kti)),
+            Err(e) => return Err(e.into()),
+        };
+        if cache.options == detection_options(kti) {
+            Ok(cache)
+        } else {
+            Ok(Cache::new(kti))
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let file = fs::File::create(path)?;
+        serde_json::to_writer(file, self)?;
+        Ok(())
+    }
+
+    /// Detects the file like `get_correct_extension`, reusing the cached result
+    /// when the file's size and fingerprint still match.
+    pub fn detect(&mut self, path: &Path, kti: &Kti) -> Result<Option<Detection>, Box<dyn Error>> {
+        let key = std::path::absolute(path)?;
+        let size = fs::metadata(path)?.len();
+        let fingerprint = fingerprint(path)?;
+        if let Some(entry) = self.entries.get(&key)
+            && entry.size == size
+            && entry.fingerprint == fingerprint
+        {
+            return Ok(entry.detection.clone());
+        }
+
+        let detection = get_correct_extension(path, kti)?;
+        self.entries.insert(
+            key,
+            Entry {
+                size,
+                fingerprint,
+                detection: detection.clone(),
+            },
+        );
+        Ok(detection)
+    }
+
+    /// Moves the entry of a renamed file to its new path.
+    pub fn renamed(&mut self, old: &Path, new: &Path) {
+        if let (Ok(old), Ok(new)) = (std::path::absolute(old), std::path::absolute(new))
+            && let Some(entry) = self.entries.remove(&old)
+        {
+            self.entries.insert(new, entry);
+        }
+    }
+}
+
+// The features decide which formats can be detected at all.
+const FEATURES: [(&str, bool); 8] = [
+    ("android", cfg!(feature = "android")),
+    ("crypto", cfg!(feature = "crypto")),
+    ("daw", cfg!(feature = "daw")),
+    ("data", cfg!(feature = "data")),
+    ("forensics", cfg!(feature = "forensics")),
+    ("gameassets", cfg!(feature = "gameassets")),
+    ("gis", cfg!(feature = "gis")),
+    ("vm", cfg!(feature = "vm")),
+];
+
+// Another version of kti may detect files differently too, so the version and
+// features it was built with are part of the options.
+fn detection_options(kti: &Kti) -> String {
+    let features: Vec<&str> = FEATURES
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(feature, _)| *feature)
+        .collect();
+    format!(
+        "version={} features={} deep={} deep-bin={} fuzzy={} text-fallback={} rename-text={} classify-binary={} validate={} min-confidence={:?} min-confidence-per-format={:?} safe={} rename-scripts={} script-map={:?} flatbuffer-ids={:?}",
+        env!("CARGO_PKG_VERSION"),
+        features.join(","),
+        kti.deep,
+        kti.deep_bin,
+        kti.fuzzy,
+        kti.text_fallback,
+        kti.rename_text,
+        kti.classify_binary,
+        kti.validate,
+        kti.min_confidence,
+        kti.min_confidence_per_format,
+        kti.safe,
+        kti.rename_scripts,
+        kti.script_map,
+        kti.flatbuffer_ids,
+    )
+}
+
+// FNV-1a, which is stable across builds unlike the standard library's hasher.
+pub(crate) fn fingerprint(path: &Path) -> io::Result<u64> {
+    let mut head = Vec::new();
+    fs::File::open(path)?
+        .take(FINGERPRINT_LEN)
+        .read_to_end(&mut head)?;
+    Ok(head.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{TempDir, kti};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn touched_but_unchanged_files_are_not_detected_again() {
+        let dir = TempDir::new();
+        let path = dir.file("a", b"GIF89a\x01\0\x01\0\0\0\0;");
+        let kti = kti(&[]);
+        let mut cache = Cache::new(&kti);
+        assert_eq!(cache.detect(&path, &kti).unwrap().unwrap().extension, "gif");
+
+        // A result only the cache could know shows whether it was used.
+        let key = std::path::absolute(&path).unwrap();
+        let entry = cache.entries.get_mut(&key).unwrap();
+        entry.detection.as_mut().unwrap().extension = "cached".to_string();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(
+            cache.detect(&path, &kti).unwrap().unwrap().extension,
+            "cached"
+        );
+
+        fs::write(&path, b"GIF87a\x01\0\x01\0\0\0\0;").unwrap();
+        assert_eq!(cache.detect(&path, &kti).unwrap().unwrap().extension, "gif");
+    }
+
+    #[test]
+    fn caches_made_with_other_flags_are_started_over() {
+        let dir = TempDir::new();
+        let path = dir.file("a", b"GIF89a\x01\0\x01\0\0\0\0;");
+        let cache_path = dir.path().join("cache.json");
+        let plain = kti(&[]);
+        let deep = kti(&["--deep"]);
+        let mut cache = Cache::load(&cache_path, &plain).unwrap();
+        cache.detect(&path, &plain).unwrap();
+        cache.save(&cache_path).unwrap();
+
+        assert_eq!(Cache::load(&cache_path, &plain).unwrap().entries.len(), 1);
+        assert!(Cache::load(&cache_path, &deep).unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn the_version_is_part_of_the_options() {
+        let options = detection_options(&kti(&[]));
+        assert!(options.starts_with(&format!("version={} ", env!("CARGO_PKG_VERSION"))));
+    }
+}
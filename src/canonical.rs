@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::fs;
+
+// Extensions that have a short and a long spelling.
+const SPELLINGS: [(&str, &str); 3] = [("jpg", "jpeg"), ("tif", "tiff"), ("mpg", "mpeg")];
+
+/// The extension each detected type should be renamed to, given with
+/// --canonical as either a preset or a mapping file.
+#[derive(Debug, Clone)]
+pub struct CanonicalMap {
+    map: HashMap<String, String>,
+}
+
+impl CanonicalMap {
+    pub fn apply(&self, extension: String) -> String {
+        match self.map.get(&extension) {
+            Some(preferred) => preferred.clone(),
+            None => extension,
+        }
+    }
+}
+
+/// Accepts the presets `short` (jpg, tif, mpg) and `long` (jpeg, tiff, mpeg)
+/// or the path of a file with one `detected = preferred` pair per line.
+/// Empty lines and lines starting with `#` are ignored.
+pub fn parse_canonical(value: &str) -> Result<CanonicalMap, String> {
+    let pairs = match value {
+        "short" => SPELLINGS.map(|(short, long)| (long.to_string(), short.to_string())),
+        "long" => SPELLINGS.map(|(short, long)| (short.to_string(), long.to_string())),
+        path => {
+            let contents = fs::read_to_string(path).map_err(|e| {
+                format!("'{path}' is not a preset (short, long) and could not be read: {e}")
+            })?;
+            return parse_mapping(&contents, "detected = preferred")
+                .map(|map| CanonicalMap { map });
+        }
+    };
+    Ok(CanonicalMap {
+        map: pairs.into_iter().collect(),
+    })
+}
+
+/// Reads `key = value` lines, where `shape` is how the lines of the file at
+/// hand look, like `detected = preferred`, for the errors.
+pub(crate) fn parse_mapping(
+    contents: &str,
+    shape: &str,
+) -> Result<HashMap<String, String>, String> {
+    let mut map = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!(
+                "line {}: expected '{shape}', got '{line}'",
+                number + 1
+            ));
+        };
+        let key = key.trim().trim_start_matches('.').to_lowercase();
+        let value = value.trim().trim_start_matches('.').to_string();
+        if key.is_empty() || value.is_empty() {
+            return Err(format!(
+                "line {}: expected '{shape}', got '{line}'",
+                number + 1
+            ));
+        }
+        map.insert(key, value);
+    }
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn presets_pick_one_spelling() {
+        let long = parse_canonical("long").unwrap();
+        assert_eq!(long.apply("jpg".to_string()), "jpeg");
+        assert_eq!(long.apply("tif".to_string()), "tiff");
+        assert_eq!(long.apply("jpeg".to_string()), "jpeg");
+        let short = parse_canonical("short").unwrap();
+        assert_eq!(short.apply("mpeg".to_string()), "mpg");
+        assert_eq!(short.apply("png".to_string()), "png");
+    }
+
+    #[test]
+    fn mapping_files_are_read() {
+        let dir = TempDir::new();
+        let path = dir.file("canonical.txt", b"# mine\n\n.JPG = jpe\ntif=tiff\n");
+        let map = parse_canonical(path.to_str().unwrap()).unwrap();
+        assert_eq!(map.apply("jpg".to_string()), "jpe");
+        assert_eq!(map.apply("tif".to_string()), "tiff");
+
+        let error = parse_canonical("missing-preset").unwrap_err();
+        assert!(error.contains("not a preset"), "{error}");
+        let path = dir.file("broken.txt", b"jpg jpeg\n");
+        let error = parse_canonical(path.to_str().unwrap()).unwrap_err();
+        assert_eq!(
+            error,
+            "line 1: expected 'detected = preferred', got 'jpg jpeg'"
+        );
+    }
+}
@@ -0,0 +1,89 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+// How much of a file --detect-charset looks at.
+const CHARSET_WINDOW: u64 = 64 * 1024;
+
+// Byte order marks, the UTF-32 ones first as they start like the UTF-16 ones.
+const BOMS: [(&[u8], &str); 5] = [
+    (&[0xFF, 0xFE, 0x00, 0x00], "utf-32le"),
+    (&[0x00, 0x00, 0xFE, 0xFF], "utf-32be"),
+    (&[0xEF, 0xBB, 0xBF], "utf-8"),
+    (&[0xFF, 0xFE], "utf-16le"),
+    (&[0xFE, 0xFF], "utf-16be"),
+];
+
+/// Guesses the charset of a text file for --detect-charset, from its byte
+/// order mark or else from its first 64 KiB. Files with bytes that don't
+/// belong in text get no guess. Without a BOM this can only tell ascii, valid
+/// UTF-8 and, for anything else with high bytes, latin-1 apart.
+pub fn guess_charset(path: &Path) -> io::Result<Option<&'static str>> {
+    let mut head = Vec::new();
+    fs::File::open(path)?
+        .take(CHARSET_WINDOW)
+        .read_to_end(&mut head)?;
+
+    if let Some((_, charset)) = BOMS.iter().find(|(bom, _)| head.starts_with(bom)) {
+        return Ok(Some(charset));
+    }
+    if head.is_empty()
+        || head
+            .iter()
+            .any(|b| b.is_ascii_control() && !matches!(b, b'\t' | b'\n' | b'\r' | b'\x0C'))
+    {
+        return Ok(None);
+    }
+    if head.is_ascii() {
+        return Ok(Some("ascii"));
+    }
+    // The window may end in the middle of a character.
+    let valid_utf8 = match std::str::from_utf8(&head) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none() && head.len() as u64 == CHARSET_WINDOW,
+    };
+    Ok(Some(if valid_utf8 { "utf-8" } else { "latin-1" }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn charset(contents: &[u8]) -> Option<&'static str> {
+        let dir = TempDir::new();
+        guess_charset(&dir.file("text", contents)).unwrap()
+    }
+
+    #[test]
+    fn utf8_is_told_from_ascii() {
+        assert_eq!(charset(b"plain words\n"), Some("ascii"));
+        assert_eq!(charset("caf\u{e9} cr\u{e8}me\n".as_bytes()), Some("utf-8"));
+        assert_eq!(charset(b"\xEF\xBB\xBFwith a bom\n"), Some("utf-8"));
+    }
+
+    #[test]
+    fn utf16_is_found_by_its_bom() {
+        assert_eq!(charset(b"\xFF\xFEh\0i\0\n\0"), Some("utf-16le"));
+        assert_eq!(charset(b"\xFE\xFF\0h\0i\0\n"), Some("utf-16be"));
+        assert_eq!(charset(b"\xFF\xFE\0\0h\0\0\0"), Some("utf-32le"));
+    }
+
+    #[test]
+    fn other_high_bytes_are_latin1() {
+        assert_eq!(charset(b"caf\xE9 cr\xE8me\n"), Some("latin-1"));
+    }
+
+    #[test]
+    fn binary_files_get_no_guess() {
+        assert_eq!(charset(b"\x7FELF\x02\x01\x01\0"), None);
+        assert_eq!(charset(b""), None);
+    }
+
+    #[test]
+    fn a_character_cut_off_by_the_window_is_still_utf8() {
+        let mut text = vec![b'a'; CHARSET_WINDOW as usize - 1];
+        text.extend_from_slice("\u{e9}".as_bytes());
+        assert_eq!(charset(&text), Some("utf-8"));
+    }
+}
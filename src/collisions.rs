@@ -0,0 +1,136 @@
+use crate::rename::short_hash;
+use std::fs;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+
+#[derive(Debug)]
+struct Collision {
+    source: String,
+    target: String,
+    same_contents: bool,
+    hash: Option<String>,
+}
+
+/// The files --collision-log lists because their new name was already taken
+/// and --on-conflict skip left them alone, to sort out by hand later.
+#[derive(Debug, Default)]
+pub struct CollisionLog {
+    collisions: Vec<Collision>,
+}
+
+impl CollisionLog {
+    /// Records that `source` was skipped because `target` exists, noting
+    /// whether the two hold the same bytes and, with `hash`, a short hash of
+    /// the existing file.
+    pub fn add(&mut self, source: &Path, target: &Path, hash: bool) -> io::Result<()> {
+        self.collisions.push(Collision {
+            source: source.display().to_string(),
+            target: target.display().to_string(),
+            same_contents: same_contents(source, target)?,
+            hash: if hash {
+                Some(short_hash(target)?)
+            } else {
+                None
+            },
+        });
+        Ok(())
+    }
+
+    /// Writes one 'source<TAB>target<TAB>same|different' line per skipped
+    /// file, followed by a tab and the hash of the target if it was taken.
+    /// Tabs, newlines and backslashes in the paths are escaped so each file
+    /// stays on its own line.
+    pub fn write(&self, file: &Path) -> io::Result<()> {
+        let mut out = fs::File::create(file)?;
+        for collision in &self.collisions {
+            let contents = if collision.same_contents {
+                "same"
+            } else {
+                "different"
+            };
+            write!(
+                out,
+                "{}\t{}\t{contents}",
+                escape(&collision.source),
+                escape(&collision.target)
+            )?;
+            if let Some(hash) = &collision.hash {
+                write!(out, "\t{hash}")?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+fn escape(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for c in path.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn same_contents(first: &Path, second: &Path) -> io::Result<bool> {
+    if fs::metadata(first)?.len() != fs::metadata(second)?.len() {
+        return Ok(false);
+    }
+    let mut first = BufReader::new(fs::File::open(first)?);
+    let mut second = BufReader::new(fs::File::open(second)?);
+    let mut first_buffer = [0; 8192];
+    let mut second_buffer = [0; 8192];
+    loop {
+        let read = first.read(&mut first_buffer)?;
+        if read == 0 {
+            return Ok(true);
+        }
+        second.read_exact(&mut second_buffer[..read])?;
+        if first_buffer[..read] != second_buffer[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn skipped_files_are_logged_with_how_they_compare() {
+        let dir = TempDir::new();
+        let same = dir.file("same.txt", b"GIF89a");
+        let same_target = dir.file("same.gif", b"GIF89a");
+        let other = dir.file("other.txt", b"GIF89a");
+        let other_target = dir.file("other.gif", b"GIF87a");
+        let log_path = dir.path().join("collisions.tsv");
+
+        let mut log = CollisionLog::default();
+        log.add(&same, &same_target, false).unwrap();
+        log.add(&other, &other_target, true).unwrap();
+        log.write(&log_path).unwrap();
+
+        let hash = short_hash(&other_target).unwrap();
+        let expected = format!(
+            "{}\t{}\tsame\n{}\t{}\tdifferent\t{hash}\n",
+            same.display(),
+            same_target.display(),
+            other.display(),
+            other_target.display()
+        );
+        assert_eq!(fs::read_to_string(&log_path).unwrap(), expected);
+    }
+
+    #[test]
+    fn tabs_and_newlines_in_paths_are_escaped() {
+        assert_eq!(escape("a\tb\nc.txt"), "a\\tb\\nc.txt");
+        assert_eq!(escape("C:\\new"), "C:\\\\new");
+        assert_eq!(escape("plain.txt"), "plain.txt");
+    }
+}
@@ -0,0 +1,108 @@
+use crate::detect::Confidence;
+
+const COLUMNS: [&str; 8] = [
+    "path",
+    "name",
+    "current",
+    "detected",
+    "size",
+    "mtime",
+    "confidence",
+    "changed",
+];
+
+/// The columns picked with --report-columns, parsed up front so unknown names
+/// are rejected before any file is checked.
+#[derive(Debug, Clone)]
+pub struct ReportColumns {
+    columns: Vec<&'static str>,
+}
+
+/// The values a column can show for a single file.
+pub struct ColumnValues<'a> {
+    pub path: &'a str,
+    pub name: &'a str,
+    pub current: Option<&'a str>,
+    pub detected: Option<&'a str>,
+    pub size: Option<u64>,
+    pub mtime: Option<&'a str>,
+    pub confidence: Option<Confidence>,
+    pub changed: bool,
+}
+
+impl ReportColumns {
+    pub fn contains(&self, column: &str) -> bool {
+        self.columns.contains(&column)
+    }
+
+    pub fn row(&self, values: &ColumnValues) -> Vec<String> {
+        self.columns
+            .iter()
+            .map(|column| match *column {
+                "path" => values.path.to_string(),
+                "name" => values.name.to_string(),
+                "current" => values.current.unwrap_or("-").to_string(),
+                "detected" => values.detected.unwrap_or("-").to_string(),
+                "size" => values.size.map_or("-".to_string(), |size| size.to_string()),
+                "mtime" => values.mtime.unwrap_or("-").to_string(),
+                "confidence" => match values.confidence {
+                    Some(Confidence::High) => "high",
+                    Some(Confidence::Medium) => "medium",
+                    Some(Confidence::Low) => "low",
+                    None => "-",
+                }
+                .to_string(),
+                "changed" => values.changed.to_string(),
+                _ => String::new(),
+            })
+            .collect()
+    }
+
+    /// Fills in the changed column of a row made before the file was renamed.
+    pub fn set_changed(&self, row: &mut [String], changed: bool) {
+        if let Some(index) = self.columns.iter().position(|column| *column == "changed") {
+            row[index] = changed.to_string();
+        }
+    }
+
+    /// Lays out the rows as a table under a header of the column names, each
+    /// column as wide as its longest value. The last column isn't padded.
+    pub fn table(&self, rows: &[Vec<String>]) -> String {
+        let header: Vec<String> = self.columns.iter().map(|c| c.to_uppercase()).collect();
+        let mut widths: Vec<usize> = header.iter().map(|h| h.chars().count()).collect();
+        for row in rows {
+            for (width, value) in widths.iter_mut().zip(row) {
+                *width = (*width).max(value.chars().count());
+            }
+        }
+
+        let mut output = String::new();
+        for row in std::iter::once(&header).chain(rows) {
+            let last = row.len().saturating_sub(1);
+            for (i, (value, width)) in row.iter().zip(&widths).enumerate() {
+                if i == last {
+                    output.push_str(value);
+                } else {
+                    output.push_str(&format!("{value:<width$}  "));
+                }
+            }
+            output.push('\n');
+        }
+        output
+    }
+}
+
+/// Parses a comma separated list of columns such as `path,detected`.
+pub fn parse_report_columns(value: &str) -> Result<ReportColumns, String> {
+    let mut columns = Vec::new();
+    for name in value.split(',').map(str::trim) {
+        let Some(column) = COLUMNS.iter().find(|column| **column == name) else {
+            return Err(format!(
+                "unknown column '{name}', expected one of {}",
+                COLUMNS.join(", ")
+            ));
+        };
+        columns.push(*column);
+    }
+    Ok(ReportColumns { columns })
+}
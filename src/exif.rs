@@ -0,0 +1,305 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+// Cameras write EXIF into one of the first segments, so there is no need to
+// read further into a JPEG than this.
+const JPEG_SCAN_LIMIT: u64 = 256 * 1024;
+const TAG_MAKE: u16 = 0x010F;
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_DNG_VERSION: u16 = 0xC612;
+
+/// Camera raw formats built on TIFF and the maker they can be told apart by.
+pub const RAW_MAKERS: [(&str, &str); 2] = [("NIKON", "nef"), ("SONY", "arw")];
+
+/// A few EXIF fields shown with --deep to help sort photos. They never affect
+/// the detected extension.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Exif {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub orientation: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub make: Option<String>,
+}
+
+impl Exif {
+    pub fn describe_orientation(&self) -> Option<&'static str> {
+        Some(match self.orientation? {
+            1 => "normal",
+            2 => "mirrored",
+            3 => "rotated 180°",
+            4 => "mirrored and rotated 180°",
+            5 => "mirrored and rotated 90° counterclockwise",
+            6 => "rotated 90° clockwise",
+            7 => "mirrored and rotated 90° clockwise",
+            8 => "rotated 90° counterclockwise",
+            _ => "unknown",
+        })
+    }
+}
+
+/// The parts of a TIFF's first directory kti looks at.
+struct TiffInfo {
+    exif: Exif,
+    dng_version: bool,
+}
+
+/// Tells camera raw files apart from plain TIFF with --deep: Canon marks CR2
+/// files right after the header, DNG files carry a DNGVersion tag and NEF
+/// and ARW are recognized by the camera make. Returns `None` for a TIFF that
+/// is none of these.
+pub fn detect_raw(path: &Path, head: &[u8]) -> Option<&'static str> {
+    if head.get(8..10) == Some(b"CR") {
+        return Some("cr2");
+    }
+    let file = fs::File::open(path).ok()?;
+    let info = parse_tiff(file_reader(&file))?;
+    if info.dng_version {
+        return Some("dng");
+    }
+    let make = info.exif.make?.to_uppercase();
+    RAW_MAKERS
+        .iter()
+        .find(|(maker, _)| make.starts_with(maker))
+        .map(|(_, extension)| *extension)
+}
+
+// The first directory of a TIFF can be anywhere in the file, so it is read in
+// place instead of loading the whole image.
+fn file_reader(file: &fs::File) -> impl Fn(usize, usize) -> Option<Vec<u8>> {
+    move |offset, len| {
+        let mut file = file;
+        file.seek(SeekFrom::Start(offset as u64)).ok()?;
+        let mut buffer = vec![0; len];
+        file.read_exact(&mut buffer).ok()?;
+        Some(buffer)
+    }
+}
+
+/// Reads EXIF from a file detected as `extension`. Broken or missing EXIF is
+/// not an error, there is simply nothing to report.
+pub fn read_exif(path: &Path, extension: &str) -> Option<Exif> {
+    let file = fs::File::open(path).ok()?;
+    let info = match extension {
+        "jpg" => {
+            let mut buffer = Vec::new();
+            (&file)
+                .take(JPEG_SCAN_LIMIT)
+                .read_to_end(&mut buffer)
+                .ok()?;
+            let tiff = find_jpeg_exif(&buffer)?;
+            parse_tiff(|offset, len| {
+                tiff.get(offset..offset.checked_add(len)?)
+                    .map(<[u8]>::to_vec)
+            })
+        }
+        "tif" | "cr2" | "dng" | "nef" | "arw" => parse_tiff(file_reader(&file)),
+        _ => None,
+    }?;
+    if info.exif.orientation.is_none() && info.exif.make.is_none() {
+        return None;
+    }
+    Some(info.exif)
+}
+
+// Walks the JPEG segments up to the image data looking for the APP1 segment
+// that holds EXIF, which is a small TIFF file of its own.
+fn find_jpeg_exif(buf: &[u8]) -> Option<&[u8]> {
+    let mut offset = 2;
+    while offset + 4 <= buf.len() {
+        if buf[offset] != 0xFF {
+            return None;
+        }
+        let marker = buf[offset + 1];
+        // Start of scan, the image data follows.
+        if marker == 0xDA {
+            return None;
+        }
+        let length = u16::from_be_bytes([buf[offset + 2], buf[offset + 3]]) as usize;
+        let segment = buf.get(offset + 4..offset + 2 + length)?;
+        if marker == 0xE1
+            && let Some(tiff) = segment.strip_prefix(b"Exif\0\0")
+        {
+            return Some(tiff);
+        }
+        offset += 2 + length;
+    }
+    None
+}
+
+/// Reads the first image directory of TIFF data through `read`, which returns
+/// `len` bytes at `offset` of the TIFF data.
+fn parse_tiff(read: impl Fn(usize, usize) -> Option<Vec<u8>>) -> Option<TiffInfo> {
+    let header = read(0, 8)?;
+    let big_endian = match header[0..4] {
+        [b'I', b'I', 0x2A, 0x00] => false,
+        [b'M', b'M', 0x00, 0x2A] => true,
+        _ => return None,
+    };
+    let u16_at = |buf: &[u8], offset: usize| {
+        let bytes = [buf[offset], buf[offset + 1]];
+        if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        }
+    };
+    let u32_at = |buf: &[u8], offset: usize| {
+        let bytes = [
+            buf[offset],
+            buf[offset + 1],
+            buf[offset + 2],
+            buf[offset + 3],
+        ];
+        if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    };
+
+    let directory = u32_at(&header, 4) as usize;
+    let count = u16_at(&read(directory, 2)?, 0) as usize;
+    let entries = read(directory + 2, count * 12)?;
+    let mut exif = Exif {
+        orientation: None,
+        make: None,
+    };
+    let mut dng_version = false;
+    for entry in entries.chunks_exact(12) {
+        match u16_at(entry, 0) {
+            TAG_ORIENTATION => exif.orientation = Some(u16_at(entry, 8)),
+            TAG_DNG_VERSION => dng_version = true,
+            TAG_MAKE => {
+                let length = u32_at(entry, 4) as usize;
+                // Values of up to four bytes are stored in the entry itself.
+                let make = if length <= 4 {
+                    entry[8..8 + length].to_vec()
+                } else {
+                    read(u32_at(entry, 8) as usize, length.min(256))?
+                };
+                let make = String::from_utf8_lossy(&make)
+                    .trim_end_matches('\0')
+                    .trim()
+                    .to_string();
+                if !make.is_empty() {
+                    exif.make = Some(make);
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(TiffInfo { exif, dng_version })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{TempDir, detection};
+
+    // A little endian TIFF whose first directory holds `entries`, each a tag,
+    // a type, a count and a value, followed by `data` for values too long to
+    // fit in an entry.
+    fn tiff(entries: &[(u16, u16, u32, u32)], data: &[u8]) -> Vec<u8> {
+        let mut tiff = b"II\x2A\0\x08\0\0\0".to_vec();
+        tiff.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        for (tag, kind, count, value) in entries {
+            tiff.extend_from_slice(&tag.to_le_bytes());
+            tiff.extend_from_slice(&kind.to_le_bytes());
+            tiff.extend_from_slice(&count.to_le_bytes());
+            tiff.extend_from_slice(&value.to_le_bytes());
+        }
+        tiff.extend_from_slice(&[0; 4]);
+        tiff.extend_from_slice(data);
+        tiff
+    }
+
+    // Where `tiff` puts its data when the directory holds `entries` entries.
+    fn data_offset(entries: u32) -> u32 {
+        8 + 2 + 12 * entries + 4
+    }
+
+    fn raw(contents: &[u8]) -> Option<&'static str> {
+        let dir = TempDir::new();
+        let path = dir.file("photo", contents);
+        detect_raw(&path, &contents[..contents.len().min(32)])
+    }
+
+    #[test]
+    fn cr2_is_recognized_by_its_marker() {
+        // Canon writes "CR", a major and a minor version after the header.
+        let mut cr2 = b"II\x2A\0\x10\0\0\0CR\x02\0\0\0\0\0".to_vec();
+        cr2.extend_from_slice(&[0; 6]);
+        assert_eq!(raw(&cr2), Some("cr2"));
+    }
+
+    #[test]
+    fn dng_is_recognized_by_its_version_tag() {
+        let dng = tiff(&[(TAG_DNG_VERSION, 1, 4, 0x0000_0401)], &[]);
+        assert_eq!(raw(&dng), Some("dng"));
+    }
+
+    #[test]
+    fn nef_and_arw_are_recognized_by_the_make() {
+        let make = b"NIKON CORPORATION\0";
+        let nef = tiff(&[(TAG_MAKE, 2, make.len() as u32, data_offset(1))], make);
+        assert_eq!(raw(&nef), Some("nef"));
+        let sony = tiff(&[(TAG_MAKE, 2, 5, data_offset(1))], b"SONY\0");
+        assert_eq!(raw(&sony), Some("arw"));
+    }
+
+    #[test]
+    fn other_tiffs_are_not_raw() {
+        let canon = tiff(&[(TAG_MAKE, 2, 6, data_offset(1))], b"Canon\0");
+        assert_eq!(raw(&canon), None);
+        assert_eq!(raw(&tiff(&[], &[])), None);
+    }
+
+    // A JPEG holding `tiff` as its EXIF segment, ahead of a quantization
+    // table and the start of the image data.
+    fn jpeg_with_exif(tiff: &[u8]) -> Vec<u8> {
+        let mut jpeg = b"\xFF\xD8\xFF\xE1".to_vec();
+        jpeg.extend_from_slice(&(tiff.len() as u16 + 8).to_be_bytes());
+        jpeg.extend_from_slice(b"Exif\0\0");
+        jpeg.extend_from_slice(tiff);
+        jpeg.extend_from_slice(b"\xFF\xDB\0\x03\0\xFF\xDA\0\x02\xFF\xD9");
+        jpeg
+    }
+
+    #[test]
+    fn jpeg_exif_is_reported_with_deep() {
+        let entries = [(TAG_MAKE, 2, 6, data_offset(2)), (TAG_ORIENTATION, 3, 1, 6)];
+        let jpeg = jpeg_with_exif(&tiff(&entries, b"Canon\0"));
+
+        let found = detection(&jpeg, &["--deep"]).unwrap();
+        assert_eq!(found.extension, "jpg");
+        let exif = found.exif.unwrap();
+        assert_eq!(exif.make.as_deref(), Some("Canon"));
+        assert_eq!(exif.orientation, Some(6));
+        assert_eq!(exif.describe_orientation(), Some("rotated 90° clockwise"));
+
+        let found = detection(&jpeg, &[]).unwrap();
+        assert_eq!(found.extension, "jpg");
+        assert!(found.exif.is_none());
+    }
+
+    #[test]
+    fn tiff_exif_is_reported_without_changing_the_type() {
+        let tiff = tiff(&[(TAG_ORIENTATION, 3, 1, 3)], &[]);
+        let found = detection(&tiff, &["--deep"]).unwrap();
+        assert_eq!(found.extension, "tif");
+        assert_eq!(found.exif.unwrap().orientation, Some(3));
+    }
+
+    #[test]
+    fn broken_exif_is_ignored() {
+        let dir = TempDir::new();
+        let jpeg = jpeg_with_exif(b"II\x2A\0\xFF\xFF\0\0");
+        let path = dir.file("photo.jpg", &jpeg);
+        assert!(read_exif(&path, "jpg").is_none());
+        let path = dir.file("plain.jpg", &jpeg_with_exif(&tiff(&[], &[])));
+        assert!(read_exif(&path, "jpg").is_none());
+        assert_eq!(detection(&jpeg, &["--deep"]).unwrap().extension, "jpg");
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Files renamed by earlier runs, read from a --journal file for
+/// --since-journal.
+#[derive(Debug, Clone)]
+pub struct RenamedFiles {
+    paths: HashSet<PathBuf>,
+}
+
+impl RenamedFiles {
+    /// Whether `path` is where an earlier run put a renamed file.
+    pub fn contains(&self, path: &Path) -> bool {
+        std::path::absolute(path).is_ok_and(|path| self.paths.contains(&path))
+    }
+}
+
+/// Adds a rename to the journal at `journal` as an `old<TAB>new` line. Both
+/// paths are made absolute so the journal still matches when kti is started
+/// from another directory.
+pub fn record(journal: &Path, old: &Path, new: &Path) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(journal)?;
+    writeln!(
+        file,
+        "{}\t{}",
+        std::path::absolute(old)?.display(),
+        std::path::absolute(new)?.display()
+    )
+}
+
+/// Reads the new paths from a journal written with --journal. A journal that
+/// doesn't exist yet has no renames, so the same file can be passed to both
+/// flags from the first run on.
+pub fn parse_since_journal(value: &str) -> Result<RenamedFiles, String> {
+    let contents = match fs::read_to_string(value) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("could not read '{value}': {e}")),
+    };
+    let paths = parse_entries(&contents)?
+        .into_iter()
+        .map(|(_, new)| new)
+        .collect();
+    Ok(RenamedFiles { paths })
+}
+
+fn parse_entries(contents: &str) -> Result<Vec<(PathBuf, PathBuf)>, String> {
+    let mut entries = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let Some((old, new)) = line.split_once('\t') else {
+            return Err(format!(
+                "line {}: expected 'old path<TAB>new path', got '{line}'",
+                number + 1
+            ));
+        };
+        entries.push((PathBuf::from(old), PathBuf::from(new)));
+    }
+    Ok(entries)
+}
+
+/// The renames of several journals combined by `kti journal merge`.
+#[derive(Debug, Default)]
+pub struct Merged {
+    /// Each file's original path and where it ended up, in the order the files
+    /// were first renamed.
+    pub renames: Vec<(PathBuf, PathBuf)>,
+    /// Renames that don't fit with the others, such as two files that ended
+    /// up at the same path.
+    pub warnings: Vec<String>,
+}
+
+/// Combines `journals`, given oldest first, into one list of renames. A file
+/// that was renamed again by a later run, like `a -> b` and then `b -> c`,
+/// gives a single `a -> c`, and renames listed more than once are kept once.
+pub fn merge(journals: &[PathBuf]) -> Result<Merged, String> {
+    let mut merged = Merged::default();
+    let mut seen = HashSet::new();
+    // The index of the rename whose file is now at a path.
+    let mut ends: HashMap<PathBuf, usize> = HashMap::new();
+    // Where the file last renamed away from a path went.
+    let mut renamed_from: HashMap<PathBuf, PathBuf> = HashMap::new();
+
+    for journal in journals {
+        let contents = fs::read_to_string(journal)
+            .map_err(|e| format!("could not read {}: {e}", journal.display()))?;
+        let entries =
+            parse_entries(&contents).map_err(|e| format!("{}: {e}", journal.display()))?;
+        for (old, new) in entries {
+            let index = match ends.remove(&old) {
+                Some(index) => {
+                    merged.renames[index].1 = new.clone();
+                    index
+                }
+                // The same journal given twice, or one that was copied into
+                // another.
+                None if seen.contains(&(old.clone(), new.clone())) => continue,
+                None => {
+                    // Without a rename back to it in between, there's no file
+                    // left at the old path. The journals may not have been
+                    // given in the order they were written.
+                    if let Some(earlier) = renamed_from.get(&old) {
+                        merged.warnings.push(format!(
+                            "{} was renamed twice, to {} and to {}",
+                            old.display(),
+                            earlier.display(),
+                            new.display()
+                        ));
+                    }
+                    merged.renames.push((old.clone(), new.clone()));
+                    merged.renames.len() - 1
+                }
+            };
+            if let Some(other) = ends.insert(new.clone(), index) {
+                merged.warnings.push(format!(
+                    "{} and {} were both renamed to {}",
+                    merged.renames[other].0.display(),
+                    merged.renames[index].0.display(),
+                    new.display()
+                ));
+            }
+            renamed_from.insert(old.clone(), new.clone());
+            renamed_from.remove(&new);
+            seen.insert((old, new));
+        }
+    }
+    // A file renamed back to where it started hasn't changed at all.
+    merged.renames.retain(|(old, new)| old != new);
+    Ok(merged)
+}
+
+/// Writes renames in the format --journal uses.
+pub fn write_entries(out: &mut impl Write, renames: &[(PathBuf, PathBuf)]) -> io::Result<()> {
+    for (old, new) in renames {
+        writeln!(out, "{}\t{}", old.display(), new.display())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn since_journal_reads_the_new_paths() {
+        let dir = TempDir::new();
+        let journal = dir.file("journal.tsv", b"/photos/a.txt\t/photos/a.jpg\n\n");
+        let renamed = parse_since_journal(journal.to_str().unwrap()).unwrap();
+        assert!(renamed.contains(Path::new("/photos/a.jpg")));
+        assert!(!renamed.contains(Path::new("/photos/a.txt")));
+
+        let missing = dir.path().join("missing.tsv");
+        let renamed = parse_since_journal(missing.to_str().unwrap()).unwrap();
+        assert!(renamed.paths.is_empty());
+        let broken = dir.file("broken.tsv", b"/photos/a.txt /photos/a.jpg\n");
+        let error = parse_since_journal(broken.to_str().unwrap()).unwrap_err();
+        assert_eq!(
+            error,
+            "line 1: expected 'old path<TAB>new path', got '/photos/a.txt /photos/a.jpg'"
+        );
+    }
+
+    fn merged(journals: &[&[u8]]) -> Merged {
+        let dir = TempDir::new();
+        let paths: Vec<PathBuf> = journals
+            .iter()
+            .enumerate()
+            .map(|(i, contents)| dir.file(&format!("{i}.tsv"), contents))
+            .collect();
+        merge(&paths).unwrap()
+    }
+
+    fn renames(merged: &Merged) -> Vec<(&str, &str)> {
+        merged
+            .renames
+            .iter()
+            .map(|(old, new)| (old.to_str().unwrap(), new.to_str().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn chained_renames_are_collapsed() {
+        let merged = merged(&[b"/a\t/b\n/x\t/y\n", b"/b\t/c\n", b"/c\t/d\n/y\t/x\n"]);
+        assert_eq!(renames(&merged), [("/a", "/d")]);
+        assert!(merged.warnings.is_empty());
+    }
+
+    #[test]
+    fn repeated_renames_are_kept_once() {
+        let journal: &[u8] = b"/a\t/b\n/c\t/d\n";
+        let merged = merged(&[journal, journal]);
+        assert_eq!(renames(&merged), [("/a", "/b"), ("/c", "/d")]);
+        assert!(merged.warnings.is_empty());
+    }
+
+    #[test]
+    fn conflicting_chains_are_warned_about() {
+        let merged = merged(&[b"/a\t/b\n", b"/a\t/c\n/x\t/c\n"]);
+        assert_eq!(
+            merged.warnings,
+            [
+                "/a was renamed twice, to /b and to /c",
+                "/a and /x were both renamed to /c"
+            ]
+        );
+    }
+
+    #[test]
+    fn merged_journals_are_written_like_journals() {
+        let mut out = Vec::new();
+        let renames = [(PathBuf::from("/a"), PathBuf::from("/d"))];
+        write_entries(&mut out, &renames).unwrap();
+        assert_eq!(out, b"/a\t/d\n");
+        assert_eq!(parse_entries("/a\t/d\n").unwrap(), renames);
+
+        let dir = TempDir::new();
+        let broken = dir.file("broken.tsv", b"/a /b\n");
+        let error = merge(std::slice::from_ref(&broken)).unwrap_err();
+        assert_eq!(
+            error,
+            format!(
+                "{}: line 1: expected 'old path<TAB>new path', got '/a /b'",
+                broken.display()
+            )
+        );
+    }
+}
@@ -1,16 +1,75 @@
-use clap::{CommandFactory, Parser};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use clap_complete::Shell;
 use owo_colors::OwoColorize;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
+use std::ffi::OsStr;
 use std::fs;
-use std::io::Read;
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 use walkdir::{DirEntry, WalkDir};
 
+mod archive;
+mod bundle;
+mod cache;
+mod canonical;
+mod charset;
+mod collisions;
+mod columns;
+mod detect;
+mod exif;
+mod journal;
+mod manifest;
+mod profile;
+mod rename;
+mod renamemap;
+mod resume;
+mod rules;
+mod selftest;
+mod skiplist;
+mod template;
+#[cfg(test)]
+mod testutil;
+mod unknown;
+mod watch;
+
+use archive::{Contents, scan_archive};
+use bundle::bundle_type;
+use cache::Cache;
+use canonical::{CanonicalMap, parse_canonical};
+use charset::guess_charset;
+use collisions::CollisionLog;
+use columns::{ColumnValues, ReportColumns, parse_report_columns};
+use detect::{
+    Check, Confidence, ConfidenceThresholds, FlatbufferIds, ScriptMap, TimedDetector,
+    get_correct_extension, parse_confidence_thresholds, parse_flatbuffer_ids, parse_script_map,
+    trace_detection,
+};
+use exif::{Exif, RAW_MAKERS};
+use journal::{RenamedFiles, parse_since_journal};
+use rename::{
+    Action, ConflictPolicy, ExtCase, SuffixMode, UnicodeForm, XattrMode, apply_ext_case,
+    create_backup, destination_path, lowercase_file_name, move_file, normalize_file_stem,
+    parse_replacement, rename_via_temp, resolve_conflict, resolve_conflict_by_hash,
+    sanitize_file_stem, write_detected_xattr,
+};
+use renamemap::{MapFormat, RenameMap};
+use resume::{Resume, parse_resume};
+use rules::{ExtensionRules, ExtensionSet, RuleAction, parse_extension_rules, parse_extension_set};
+use skiplist::{SkipList, parse_skip_list};
+use template::{ReportTemplate, TemplateValues, parse_template};
+use unknown::UnknownFiles;
+
 #[derive(Debug, Parser, Clone)]
 #[command(name = "kti")]
 #[command(about = "A simple tool to correct file extensions to match their file signatures.")]
 struct Kti {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     path: Option<PathBuf>,
 
     #[arg(
@@ -29,6 +88,13 @@ struct Kti {
     )]
     max_depth: Option<usize>,
 
+    #[arg(
+        long = "rename-max-depth",
+        value_name = "INTEGER",
+        help = "Only renames files up to this depth, deeper files are still reported"
+    )]
+    rename_max_depth: Option<usize>,
+
     #[arg(
         short = 'd',
         long = "only-diff",
@@ -43,303 +109,4597 @@ struct Kti {
     )]
     silent: bool,
 
+    #[arg(
+        short = 'v',
+        long = "verbose",
+        global = true,
+        help = "Prints more details, like photo EXIF data with --deep and why a detection was inconclusive"
+    )]
+    verbose: bool,
+
+    #[arg(
+        long = "exclude-dir",
+        value_name = "DIR",
+        help = "Skips directories with this name, or at this path relative to the scanned directory, and everything inside them. Can be given more than once"
+    )]
+    exclude_dirs: Vec<PathBuf>,
+
+    #[arg(
+        long = "detect-bundles",
+        help = "Reports directories that are macOS or iOS bundles, such as .app or .framework, with their bundle type"
+    )]
+    detect_bundles: bool,
+
+    #[arg(
+        long = "skip-bundles",
+        requires = "detect_bundles",
+        help = "Doesn't check the files inside bundles found by --detect-bundles"
+    )]
+    skip_bundles: bool,
+
+    #[arg(
+        long = "since",
+        value_name = "DURATION",
+        value_parser = parse_duration,
+        help = "Only checks files modified within the given time, e.g. 30m, 24h or 7d"
+    )]
+    since: Option<Duration>,
+
+    #[arg(
+        long = "relative-paths",
+        conflicts_with = "absolute",
+        help = "Prints paths relative to the scanned directory"
+    )]
+    relative_paths: bool,
+
+    #[arg(long = "absolute", help = "Prints full canonical paths")]
+    absolute: bool,
+
+    #[arg(
+        long = "report-relative-to",
+        value_name = "DIR",
+        value_parser = parse_report_base,
+        conflicts_with_all = ["relative_paths", "absolute"],
+        help = "Prints paths relative to DIR instead of the scanned directory, using ../ where needed"
+    )]
+    report_relative_to: Option<PathBuf>,
+
+    #[arg(
+        long = "canonicalize-root",
+        help = "Resolves the scanned directory to its canonical absolute path first, so the same tree is reported the same way however it was reached"
+    )]
+    canonicalize_root: bool,
+
     #[arg(short = 'L', long = "follow-links", help = "Follows symbolic links")]
     follow_links: bool,
 
+    #[arg(
+        long = "check-link-targets",
+        help = "Also applies the hidden file rules to the targets of symbolic links"
+    )]
+    check_link_targets: bool,
+
     #[arg(long = "dry-run", help = "Runs kti without any changes to the files")]
     dry_run: bool,
 
-    #[arg(short = 'c', long = "color", help = "Adds colors to the output.")]
-    colored: bool,
+    #[arg(
+        long = "two-pass",
+        conflicts_with = "watch",
+        help = "Checks the whole tree first and only then renames, removes or tags files"
+    )]
+    two_pass: bool,
 
     #[arg(
-        long = "generate-completions",
+        long = "resume",
+        value_name = "STATE",
+        value_parser = parse_resume,
+        conflicts_with_all = ["watch", "two_pass"],
+        help = "Saves how far the scan got to the file STATE and continues from there when run again after an interruption"
+    )]
+    resume: Option<Resume>,
+
+    #[arg(
+        long = "rename-retry",
+        value_name = "N",
+        help = "Retries a rename that failed with a transient error, like a stale network file handle, up to N times"
+    )]
+    rename_retry: Option<u32>,
+
+    #[arg(
+        long = "dry-run-apply-preview",
+        conflicts_with_all = ["audit", "format", "report_template"],
+        help = "Runs kti like --dry-run and ends with counts of what running it for real would do"
+    )]
+    apply_preview: bool,
+
+    #[arg(
+        long = "action",
+        value_name = "ACTION",
         value_enum,
-        help = "Generate shell completions"
+        default_value_t = Action::Report,
+        help = "What to do with files whose extension doesn't match"
     )]
-    generate_completions: Option<Shell>,
-}
+    action: Action,
 
-fn main() {
-    let kti = Kti::parse();
+    #[arg(
+        long = "dest",
+        value_name = "DIR",
+        required_if_eq_any = [("action", "copy"), ("action", "move")],
+        help = "The directory --action copy and move put the files in"
+    )]
+    dest: Option<PathBuf>,
 
-    if let Some(shell) = kti.generate_completions {
-        let mut cmd = Kti::command();
-        let name = cmd.get_name().to_string();
-        eprintln!("Generating completion file for {shell}");
-        clap_complete::generate(shell, &mut cmd, name, &mut std::io::stdout());
-        return;
-    }
+    #[arg(
+        long = "group-errors",
+        help = "Prints all errors together at the end with counts by kind"
+    )]
+    group_errors: bool,
 
-    let root_path = kti.path.clone().unwrap_or(PathBuf::from("."));
+    #[arg(
+        long = "fail-fast",
+        help = "Stops at the first file that could not be renamed"
+    )]
+    fail_fast: bool,
 
-    if let Ok(exists) = fs::exists(&root_path) {
-        if !exists {
-            eprintln!("Path does not exist.")
-        }
-        let mut walkdir = WalkDir::new(&root_path);
+    #[arg(
+        long = "max-errors",
+        visible_alias = "error-exit-threshold",
+        value_name = "N",
+        help = "Exits with an error if more than N files could not be read or renamed"
+    )]
+    max_errors: Option<usize>,
 
-        if let Some(depth) = kti.max_depth {
-            walkdir = walkdir.max_depth(depth)
-        }
+    #[arg(
+        long = "ext-case",
+        value_name = "CASE",
+        value_enum,
+        global = true,
+        default_value_t = ExtCase::Lower,
+        help = "The case of extensions written when renaming"
+    )]
+    ext_case: ExtCase,
 
-        if kti.follow_links {
-            walkdir = walkdir.follow_links(true)
-        }
+    #[arg(
+        long = "lowercase-name",
+        visible_alias = "rename-to-lowercase-stem",
+        conflicts_with = "ext_case",
+        help = "Lowercases the whole file name of files that get renamed, not only the extension"
+    )]
+    lowercase_name: bool,
 
-        let entries = walkdir.into_iter();
+    #[arg(
+        long = "normalize-unicode",
+        value_name = "FORM",
+        value_enum,
+        help = "Brings the names of files that get renamed into Unicode normalization FORM, leaving the extension as it is"
+    )]
+    normalize_unicode: Option<UnicodeForm>,
 
-        let mut diff_counter = 0;
-        for entry_result in entries.filter_entry(|e| filter_entries(e, &kti)) {
-            let entry = match entry_result {
-                Ok(entry) => entry,
-                Err(e) => {
-                    eprintln!("Error reading entry: {}", e);
-                    continue;
-                }
-            };
+    #[arg(
+        long = "sanitize",
+        visible_alias = "rename-safe-chars",
+        value_name = "CHAR",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "_",
+        value_parser = parse_replacement,
+        help = "Replaces control characters and characters Windows doesn't allow in the names of files that get renamed with CHAR, or drops them with --sanitize= [default: _]"
+    )]
+    sanitize: Option<String>,
 
-            if !entry.path().is_file() {
-                continue;
-            }
+    #[arg(
+        long = "min-confidence",
+        value_name = "LEVEL",
+        value_enum,
+        global = true,
+        help = "Only renames files detected with at least this confidence, others are still reported"
+    )]
+    min_confidence: Option<Confidence>,
 
-            let current_extension: String = match entry.path().extension() {
-                Some(ext) => ext.to_string_lossy().to_string(),
-                None => {
-                    if kti.colored {
-                        "No extension".yellow().to_string()
-                    } else {
-                        "No extension".to_string()
-                    }
-                }
-            };
+    #[arg(
+        long = "min-confidence-per-format",
+        value_name = "FILE",
+        value_parser = parse_confidence_thresholds,
+        global = true,
+        help = "Reads 'extension = level' lines from FILE, the confidence each detected format needs instead of --min-confidence"
+    )]
+    min_confidence_per_format: Option<ConfidenceThresholds>,
 
-            let detected_extension: String = match get_correct_extension(entry.path()) {
-                Ok(Some(ext)) => ext,
-                Ok(None) => {
-                    if kti.colored {
-                        "Not detected".yellow().to_string()
-                    } else {
-                        "Not detected".to_string()
-                    }
-                }
-                Err(e) => e.to_string(),
-            };
+    #[arg(
+        long = "safe",
+        global = true,
+        conflicts_with_all = ["min_confidence", "min_confidence_per_format", "on_conflict"],
+        help = "Only renames complete files detected by their signature and never replaces existing files. Same as --min-confidence high --validate --on-conflict skip"
+    )]
+    safe: bool,
 
-            let file_name = entry.file_name();
+    #[arg(
+        long = "on-conflict",
+        value_name = "POLICY",
+        global = true,
+        value_enum,
+        default_value_t = ConflictPolicy::Overwrite,
+        help = "What to do when the new name or backup name is already taken"
+    )]
+    on_conflict: ConflictPolicy,
 
-            let file_path = entry.path();
+    #[arg(
+        long = "suffix-mode",
+        value_name = "MODE",
+        value_enum,
+        default_value_t = SuffixMode::Number,
+        help = "What --on-conflict rename adds to a name that is already taken. hash needs --on-conflict rename"
+    )]
+    suffix_mode: SuffixMode,
 
-            if different_extensions(&current_extension, &detected_extension) {
-                diff_counter += 1;
-            }
-            if kti.colored {
-                print_colored_report(
-                    &file_name.to_string_lossy(),
-                    &file_path.to_string_lossy(),
-                    &kti,
-                    &current_extension,
-                    &detected_extension,
-                );
-            } else {
-                print_report(
-                    &file_name.to_string_lossy(),
-                    &file_path.to_string_lossy(),
-                    &kti,
-                    &current_extension,
-                    &detected_extension,
-                );
-            }
+    #[arg(
+        long = "concurrency-safe",
+        help = "Renames through a temporary name so an interrupted or competing rename never clobbers a file"
+    )]
+    concurrency_safe: bool,
 
-            if !kti.dry_run && different_extensions(&current_extension, &detected_extension) {
-                let mut updated_path = file_path.to_path_buf();
-                updated_path.set_extension(detected_extension);
+    #[arg(
+        long = "backup",
+        value_name = "SUFFIX",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = ".bak",
+        help = "Keeps the original under its old name plus SUFFIX before renaming [default: .bak]"
+    )]
+    backup: Option<String>,
 
-                match fs::rename(file_path, &updated_path) {
-                    Ok(_) => {
-                        println!("{:?} -> {:?}", file_path, updated_path);
-                    }
-                    Err(e) => {
-                        eprintln!("Could not rename file.");
-                        eprintln!("{}", e)
-                    }
-                };
-            }
-        }
-        println!("Differences found: {}", diff_counter);
-    } else {
-        println!("Failed reading directory")
-    }
-}
+    #[arg(
+        long = "write-xattr",
+        value_name = "MODE",
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "only",
+        help = "Stores the detected type of files that would be renamed in the user.kti.detected attribute, instead of renaming them or, with =also, as well as renaming them"
+    )]
+    write_xattr: Option<XattrMode>,
 
-fn print_colored_report(name: &str, path: &str, kti: &Kti, current: &str, detected: &str) {
-    if !kti.silent && !kti.only_different && !different_extensions(current, detected) {
-        println!();
-        println!("Path: {}", path.bright_green());
-        println!("Name: {}", name.bright_green());
-        println!("Current:  {}", current.bright_green());
-        println!("Detected: {}", detected.bright_green());
-    }
-    if !kti.silent && !kti.only_different && different_extensions(current, detected) {
-        println!();
-        println!("Path: {}", path.bright_green());
-        println!("Name: {}", name.bright_green());
-        println!("Current:  {}", current.bright_red());
-        println!("Detected: {}", detected.bright_green());
-    }
-    if !kti.silent && kti.only_different && different_extensions(current, detected) {
-        println!();
-        println!("Path: {}", path.bright_green());
-        println!("Name: {}", name.bright_green());
-        println!("Current:  {}", current.bright_red());
-        println!("Detected: {}", detected.bright_green());
-    }
-}
-
-fn print_report(name: &str, path: &str, kti: &Kti, current: &str, detected: &str) {
-    if !kti.silent {
-        if !kti.only_different && !different_extensions(current, detected) {
-            println!();
-            println!("Path: {}", path);
-            println!("Name: {}", name);
-            println!("Current:  {}", current);
-            println!("Detected: {}", detected);
-        }
-        if !kti.only_different && different_extensions(current, detected) {
-            println!();
-            println!("Path: {}", path);
-            println!("Name: {}", name);
-            println!("Current:  {}", current);
-            println!("Detected: {}", detected);
-        }
-        if kti.only_different && different_extensions(current, detected) {
-            println!();
-            println!("Path: {}", path);
-            println!("Name: {}", name);
-            println!("Current:  {}", current);
-            println!("Detected: {}", detected);
-        }
-    }
-}
-
-fn get_correct_extension(path: &Path) -> Result<Option<String>, Box<dyn Error>> {
-    let mut file = fs::File::open(path)?;
-    let mut buffer = [0; 32];
-    let bytes_read = file.read(&mut buffer)?;
-
-    let extension = match &buffer[0..std::cmp::min(bytes_read, 32)] {
-        [0x47, 0x49, 0x46, 0x38, 0x37, 0x61, ..] | [0x47, 0x49, 0x46, 0x38, 0x39, 0x61, ..] => {
-            Some("gif")
-        }
-        [0xFF, 0xFB, ..] | [0xFF, 0xF3, ..] | [0xFF, 0xF2, ..] | [0x49, 0x44, 0x33, ..] => {
-            Some("mp3")
-        }
-        [0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, ..] => Some("png"),
-        [0x25, 0x50, 0x44, 0x46, 0x2D, ..] => Some("pdf"),
-        [0x4F, 0x67, 0x67, 0x53, ..] => {
-            drop(file);
-            detect_opus_or_ogg(path)?
-        }
-        [0x1A, 0x45, 0xDF, 0xA3, ..] => {
-            drop(file);
-            detect_webm_or_mkv(path)?
-        }
-        [0x66, 0x4C, 0x61, 0x43, ..] => Some("flac"),
-        [0xFF, 0xD8, 0xFF, ..] => Some("jpg"),
-        buf if buf.len() >= 12 && &buf[0..4] == b"RIFF" => match &buf[8..12] {
-            b"WEBP" => Some("webp"),
-            b"WAVE" => Some("wav"),
-            _ => None,
-        },
-        buf if buf.len() >= 12 && &buf[4..8] == b"ftyp" => match &buf[8..12] {
-            b"qt  " => Some("mov"),
-            b"avc1" | b"isom" | b"mmp4" | b"mp41" | b"mp42" | b"mp71" | b"msnv" | b"M4V " => {
-                Some("mp4")
-            }
-            _ => None,
-        },
-        _ => None,
-    };
-    let extension = extension.map(|ext| ext.to_string());
-    Ok(extension)
-}
+    #[arg(
+        long = "prune-empty-dirs",
+        help = "Removes directories inside the path that are empty after the scan"
+    )]
+    prune_empty_dirs: bool,
 
-fn detect_opus_or_ogg(path: &Path) -> Result<Option<&str>, Box<dyn Error>> {
-    let mut file = fs::File::open(path)?;
-    let mut buffer = [0; 1024];
-    let bytes_read = file.read(&mut buffer)?;
+    #[arg(
+        long = "on-empty",
+        value_name = "POLICY",
+        value_enum,
+        help = "What to do with zero-byte files, which are otherwise reported as not detected"
+    )]
+    on_empty: Option<EmptyPolicy>,
 
-    if find_bytes_in_buffer(&buffer[0..bytes_read], b"OpusHead") {
-        Ok(Some("opus"))
-    } else {
-        Ok(Some("ogg"))
-    }
-}
+    #[arg(
+        long = "rename-hook",
+        value_name = "CMD",
+        help = "Runs CMD after each rename with the old and new path as arguments, also set as KTI_OLD_PATH and KTI_NEW_PATH"
+    )]
+    rename_hook: Option<PathBuf>,
 
-fn detect_webm_or_mkv(path: &Path) -> Result<Option<&str>, Box<dyn Error>> {
-    let mut file = fs::File::open(path)?;
-    let mut buffer = [0; 1024];
-    let bytes_read = file.read(&mut buffer)?;
+    #[arg(
+        long = "journal",
+        value_name = "FILE",
+        help = "Appends each rename to FILE as an 'old path<TAB>new path' line"
+    )]
+    journal: Option<PathBuf>,
 
-    if contains_webm_codecs(&buffer[0..bytes_read]) {
-        Ok(Some("webm"))
-    } else {
-        Ok(Some("mkv"))
-    }
-}
+    #[arg(
+        long = "rename-map",
+        visible_alias = "rename-map-output",
+        value_name = "FILE",
+        help = "Writes each rename, or each one a dry run would make, to FILE as a mapping of old to new paths"
+    )]
+    rename_map: Option<PathBuf>,
 
-fn contains_webm_codecs(buffer: &[u8]) -> bool {
-    if find_bytes_in_buffer(buffer, b"V_VP8")
-        || find_bytes_in_buffer(buffer, b"V_VP9")
-        || find_bytes_in_buffer(buffer, b"V_AV01")
-        || find_bytes_in_buffer(buffer, b"A_VORBIS")
-        || find_bytes_in_buffer(buffer, b"A_OPUS")
-    {
-        return true;
-    }
-    if find_bytes_in_buffer(buffer, b"V_MPEG4")
-        || find_bytes_in_buffer(buffer, b"V_MPEG2")
-        || find_bytes_in_buffer(buffer, b"A_AC3")
-        || find_bytes_in_buffer(buffer, b"A_DTS")
-        || find_bytes_in_buffer(buffer, b"A_AAC")
-        || find_bytes_in_buffer(buffer, b"A_MP3")
-    {
-        return false;
-    }
+    #[arg(
+        long = "rename-map-format",
+        value_name = "FORMAT",
+        value_enum,
+        default_value_t = MapFormat::Tsv,
+        requires = "rename_map",
+        help = "The format --rename-map writes"
+    )]
+    rename_map_format: MapFormat,
 
-    false
-}
+    #[arg(
+        long = "collision-log",
+        visible_alias = "rename-collision-log",
+        value_name = "FILE",
+        help = "Writes the files that were skipped because their new name was taken to FILE, with the name and whether both files are the same"
+    )]
+    collision_log: Option<PathBuf>,
 
-fn find_bytes_in_buffer(buffer: &[u8], pattern: &[u8]) -> bool {
-    buffer
-        .windows(pattern.len())
-        .any(|window| window == pattern)
-}
+    #[arg(
+        long = "collision-log-hash",
+        requires = "collision_log",
+        help = "Adds a short hash of the existing file to each line of --collision-log"
+    )]
+    collision_log_hash: bool,
 
-fn filter_entries(entry: &DirEntry, options: &Kti) -> bool {
-    if !options.show_hidden && is_hidden(entry) {
-        return false;
-    }
-    true
-}
+    #[arg(
+        long = "since-journal",
+        value_name = "FILE",
+        value_parser = parse_since_journal,
+        help = "Skips files that a run with --journal FILE already renamed"
+    )]
+    since_journal: Option<RenamedFiles>,
 
-fn is_hidden(entry: &DirEntry) -> bool {
-    entry
-        .file_name()
-        .to_str()
-        .map(|s| s.starts_with("."))
-        .unwrap_or(false)
-}
+    #[arg(
+        long = "only-extensionless",
+        help = "Only checks files that do not have an extension"
+    )]
+    only_extensionless: bool,
 
-fn different_extensions(current: &str, detected: &str) -> bool {
-    if detected.contains("No") || detected.contains("Err") {
-        return false;
-    }
-    if current == "jpeg" && detected == "jpg" {
-        return false;
-    }
-    if current == detected {
-        return false;
-    }
-    true
+    #[arg(
+        long = "on-no-extension",
+        value_name = "POLICY",
+        value_enum,
+        default_value_t = NoExtensionPolicy::Add,
+        help = "What to do with detected files that have no extension"
+    )]
+    on_no_extension: NoExtensionPolicy,
+
+    #[arg(
+        long = "per-extension-action",
+        value_name = "FILE",
+        value_parser = parse_extension_rules,
+        help = "Reads 'extensions = rename|report|skip' rules from FILE for files that have an extension"
+    )]
+    per_extension_action: Option<ExtensionRules>,
+
+    #[arg(
+        long = "strict-extension-set",
+        value_name = "FILE",
+        value_parser = parse_extension_set,
+        global = true,
+        help = "Only renames files to the extensions listed in FILE, others are reported but not renamed"
+    )]
+    strict_extension_set: Option<ExtensionSet>,
+
+    #[arg(
+        long = "prefer-existing-when-tie",
+        global = true,
+        help = "Keeps the current extension when it is one of several types the file could be"
+    )]
+    prefer_existing_when_tie: bool,
+
+    #[arg(
+        long = "fix-double-extensions",
+        help = "Removes repeated extensions like photo.jpg.jpg when the content matches the extension"
+    )]
+    fix_double_extensions: bool,
+
+    #[arg(
+        long = "deep",
+        global = true,
+        help = "Inspects file contents further to tell apart similar formats"
+    )]
+    deep: bool,
+
+    #[arg(
+        long = "scan-archives",
+        global = true,
+        help = "Lists the files inside zip and tar files, and gzip files with --deep, with their types, shown with -v and in --format json"
+    )]
+    scan_archives: bool,
+
+    #[arg(
+        long = "detect-charset",
+        help = "Guesses the charset of text files, shown with -v and in --format json"
+    )]
+    detect_charset: bool,
+
+    #[arg(
+        long = "deep-bin",
+        global = true,
+        help = "Checks files named .bin, .dat, .img or without an extension for firmware formats"
+    )]
+    deep_bin: bool,
+
+    #[arg(
+        long = "fuzzy",
+        global = true,
+        help = "Enables heuristic detection for formats without a reliable signature, which may misdetect files"
+    )]
+    fuzzy: bool,
+
+    #[arg(
+        long = "mmap",
+        global = true,
+        help = "Memory maps large files for detectors that read far into them, which can be faster on network filesystems"
+    )]
+    mmap: bool,
+
+    #[arg(
+        long = "validate",
+        global = true,
+        help = "Checks that detected files are complete and reports those that look truncated"
+    )]
+    validate: bool,
+
+    #[arg(
+        long = "text-fallback",
+        global = true,
+        help = "Reports files that are plain text but no known format as txt, without renaming them"
+    )]
+    text_fallback: bool,
+
+    #[arg(
+        long = "rename-text",
+        global = true,
+        requires = "text_fallback",
+        help = "Renames files --text-fallback recognizes as a structured text format such as json, yaml or log. Without it they are only reported"
+    )]
+    rename_text: bool,
+
+    #[arg(
+        long = "rename-scripts",
+        global = true,
+        help = "Renames scripts after the interpreter on their #! line, like py for python. Without it they are only reported with --text-fallback"
+    )]
+    rename_scripts: bool,
+
+    #[arg(
+        long = "script-map",
+        value_name = "FILE",
+        value_parser = parse_script_map,
+        global = true,
+        help = "Adds interpreters from a file of 'interpreter = extension' lines for scripts"
+    )]
+    script_map: Option<ScriptMap>,
+
+    #[arg(
+        long = "flatbuffer-ids",
+        value_name = "ID=EXT,...",
+        value_parser = parse_flatbuffer_ids,
+        global = true,
+        help = "Detects FlatBuffer files with these 4 character file identifiers, named with the extension after each ID"
+    )]
+    flatbuffer_ids: Option<FlatbufferIds>,
+
+    #[arg(
+        long = "classify-binary",
+        global = true,
+        help = "Reports a rough guess such as possibly-msgpack for unknown binary files, without renaming them"
+    )]
+    classify_binary: bool,
+
+    #[arg(short = 'c', long = "color", help = "Adds colors to the output.")]
+    colored: bool,
+
+    #[arg(
+        long = "warn-threshold",
+        visible_alias = "summary-threshold-color",
+        value_name = "N",
+        requires = "colored",
+        help = "Colors the differences found green at 0, yellow up to N and red above N"
+    )]
+    warn_threshold: Option<usize>,
+
+    #[arg(
+        long = "canonical",
+        value_name = "FILE_OR_PRESET",
+        value_parser = parse_canonical,
+        global = true,
+        help = "Renames detected types to preferred extensions, using the preset short or long or a file of 'detected = preferred' lines"
+    )]
+    canonical: Option<CanonicalMap>,
+
+    #[arg(
+        long = "format",
+        value_enum,
+        global = true,
+        default_value_t = Format::Text,
+        help = "Output format for the report"
+    )]
+    format: Format,
+
+    #[arg(
+        long = "report-template",
+        value_name = "TEMPLATE",
+        value_parser = parse_template,
+        help = "Prints each file with a custom template using {path}, {name}, {current}, {detected}, {changed} and {stem}, with \\t and \\n for tabs and newlines"
+    )]
+    report_template: Option<ReportTemplate>,
+
+    #[arg(
+        long = "report-columns",
+        value_name = "COLUMNS",
+        value_parser = parse_report_columns,
+        conflicts_with_all = ["report_template", "audit", "format", "watch", "dedupe_report"],
+        help = "Prints the report as a table of the given columns, out of path, name, current, detected, size, mtime, confidence and changed"
+    )]
+    report_columns: Option<ReportColumns>,
+
+    #[arg(
+        long = "report-utc-time",
+        help = "Includes each file's modification time in UTC in the JSON report"
+    )]
+    report_utc_time: bool,
+
+    #[arg(
+        long = "show-mtime",
+        help = "Prints each file's modification time in UTC in the text report"
+    )]
+    show_mtime: bool,
+
+    #[arg(
+        long = "report-size",
+        help = "Shows each file's size in bytes in the text and JSON report"
+    )]
+    report_size: bool,
+
+    #[arg(
+        long = "sort-by",
+        value_name = "KEY",
+        value_enum,
+        conflicts_with_all = ["watch", "audit", "dedupe_report"],
+        help = "Holds the report back until all files are checked and prints it sorted"
+    )]
+    sort_by: Option<SortKey>,
+
+    #[arg(
+        long = "manifest",
+        value_name = "FILE",
+        conflicts_with_all = ["path", "watch", "audit"],
+        help = "Checks only the files listed in FILE as 'path,extension' lines and exits with an error on any mismatch"
+    )]
+    manifest: Option<PathBuf>,
+
+    #[arg(
+        long = "audit",
+        help = "Prints counts of matching and mismatched files and lists only the mismatches, without renaming"
+    )]
+    audit: bool,
+
+    #[arg(
+        long = "dedupe-report",
+        conflicts_with_all = ["report_template", "audit", "format", "watch"],
+        help = "Groups files with the same current and detected extension into one entry with a count and a few examples"
+    )]
+    dedupe_report: bool,
+
+    #[arg(
+        long = "examples",
+        value_name = "N",
+        default_value_t = 3,
+        requires = "dedupe_report",
+        help = "How many example paths --dedupe-report shows for each group"
+    )]
+    examples: usize,
+
+    #[arg(
+        long = "stats-by-dir",
+        value_name = "N",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "10",
+        help = "Prints the N directories with the most differences at the end [default: 10]"
+    )]
+    stats_by_dir: Option<usize>,
+
+    #[arg(
+        long = "summary-json",
+        value_name = "FILE",
+        help = "Writes a summary of the run as JSON to the given file"
+    )]
+    summary_json: Option<PathBuf>,
+
+    #[arg(
+        long = "cache",
+        visible_alias = "detect-only-changed-bytes",
+        value_name = "FILE",
+        help = "Keeps detections in FILE and skips files whose size and first bytes haven't changed since"
+    )]
+    cache: Option<PathBuf>,
+
+    #[arg(
+        long = "dump-unknown",
+        visible_alias = "print-unsupported-bytes",
+        value_name = "DIR",
+        help = "Writes the first bytes of files that weren't detected to a report in DIR, grouped by identical bytes"
+    )]
+    dump_unknown: Option<PathBuf>,
+
+    #[arg(
+        long = "dump-bytes",
+        value_name = "N",
+        default_value_t = 16,
+        requires = "dump_unknown",
+        help = "How many bytes --dump-unknown reports for each file"
+    )]
+    dump_bytes: usize,
+
+    #[arg(
+        long = "read-timeout",
+        value_name = "DURATION",
+        value_parser = parse_duration,
+        conflicts_with = "cache",
+        help = "Skips files that take longer than DURATION to read, e.g. 30s, for slow network filesystems"
+    )]
+    read_timeout: Option<Duration>,
+
+    #[arg(
+        long = "detect-timeout-skip-list",
+        value_name = "FILE",
+        value_parser = parse_skip_list,
+        requires = "read_timeout",
+        help = "Adds files that timed out to FILE and skips the files listed in it"
+    )]
+    skip_list: Option<SkipList>,
+
+    #[arg(
+        long = "retry-skipped",
+        requires = "skip_list",
+        help = "Checks the files in the skip list again and removes the ones that no longer time out"
+    )]
+    retry_skipped: bool,
+
+    #[arg(
+        long = "watch",
+        value_name = "DIR",
+        conflicts_with_all = ["path", "audit", "format"],
+        help = "Keeps running and checks new files as they appear in the directory"
+    )]
+    watch: Option<PathBuf>,
+
+    #[arg(
+        long = "profile",
+        value_name = "NAME",
+        global = true,
+        help = "Adds the flags of profile NAME from the config file, flags on the command line take precedence"
+    )]
+    profile: Option<String>,
+
+    #[arg(
+        long = "config",
+        value_name = "FILE",
+        global = true,
+        requires = "profile",
+        help = "Reads profiles from FILE instead of ~/.config/kti/config"
+    )]
+    config: Option<PathBuf>,
+
+    #[arg(
+        long = "generate-completions",
+        value_enum,
+        help = "Generate shell completions"
+    )]
+    generate_completions: Option<Shell>,
+}
+
+#[derive(Debug, Subcommand, Clone)]
+enum Command {
+    #[command(about = "Shows how kti would treat a single file without changing it")]
+    Detect {
+        file: PathBuf,
+
+        #[arg(
+            long = "pretend-ext",
+            value_name = "EXT",
+            help = "Treats the file as if it had this extension"
+        )]
+        pretend_ext: Option<String>,
+    },
+
+    #[command(
+        about = "Shows what every step of the detection finds in a single file and how kti would treat it"
+    )]
+    Explain {
+        file: PathBuf,
+
+        #[arg(
+            long = "pretend-ext",
+            value_name = "EXT",
+            help = "Treats the file as if it had this extension"
+        )]
+        pretend_ext: Option<String>,
+    },
+
+    #[command(
+        about = "Checks whether two files are detected as the same type, exiting with 1 if they aren't"
+    )]
+    Compare {
+        first: PathBuf,
+        second: PathBuf,
+
+        #[arg(
+            long = "hash",
+            help = "Also compares a hash of the first 4 KiB of both files"
+        )]
+        hash: bool,
+    },
+
+    #[command(
+        alias = "self-test",
+        about = "Checks that kti still detects its built in samples of each format"
+    )]
+    Selftest,
+
+    #[command(about = "Works with the journals written by --journal")]
+    Journal {
+        #[command(subcommand)]
+        command: JournalCommand,
+    },
+}
+
+#[derive(Debug, Subcommand, Clone)]
+enum JournalCommand {
+    #[command(
+        about = "Combines journals, oldest first, into one, following files that were renamed more than once"
+    )]
+    Merge {
+        #[arg(required = true)]
+        journals: Vec<PathBuf>,
+
+        #[arg(
+            short = 'o',
+            long = "output",
+            value_name = "FILE",
+            help = "Writes the merged journal to FILE instead of printing it"
+        )]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Text,
+    Json,
+    /// One JSON object per line, written as each file is checked
+    Ndjson,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SortKey {
+    /// Largest files first
+    Size,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum NoExtensionPolicy {
+    /// Leaves the file alone and does not count it as a difference
+    Skip,
+    /// Adds the detected extension
+    Add,
+    /// Reports the file as a difference without renaming it
+    Report,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum EmptyPolicy {
+    /// Leaves zero-byte files out of the report
+    Skip,
+    /// Reports zero-byte files as a difference without touching them
+    Report,
+    /// Removes zero-byte files
+    Delete,
+}
+
+#[derive(Debug, Serialize)]
+struct FileReport {
+    path: String,
+    name: String,
+    current: Option<String>,
+    detected: Option<String>,
+    confidence: Option<Confidence>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    report_only: bool,
+    different: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    alternatives: Vec<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    truncated: bool,
+    /// Only set with --on-empty report.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    empty: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exif: Option<Exif>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    variant: Option<String>,
+    /// Only included with --scan-archives.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    contents: Option<Contents>,
+    /// Only included with --detect-charset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    charset: Option<&'static str>,
+    /// Only included with --report-size or --sort-by size.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    /// Only included with --report-utc-time, and null if the time is unknown.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modified: Option<Option<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    renamed_to: Option<String>,
+}
+
+/// A line of --format ndjson output, tagged with what it holds.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum NdjsonLine<'a> {
+    File(&'a FileReport),
+    Bundle(&'a BundleReport),
+    Summary {
+        differences: usize,
+    },
+    /// The last line of --audit, after a file line for each mismatch.
+    #[serde(rename = "summary")]
+    AuditSummary {
+        matching: usize,
+        mismatched: usize,
+        not_detected: usize,
+        no_extension: usize,
+    },
+}
+
+/// A directory found by --detect-bundles.
+#[derive(Debug, Serialize)]
+struct BundleReport {
+    path: String,
+    bundle: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonReport {
+    files: Vec<FileReport>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    bundles: Vec<BundleReport>,
+    differences: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Audit {
+    matching: usize,
+    mismatched: usize,
+    not_detected: usize,
+    no_extension: usize,
+    mismatches: Vec<FileReport>,
+}
+
+impl Audit {
+    fn ndjson_summary(&self) -> NdjsonLine<'static> {
+        NdjsonLine::AuditSummary {
+            matching: self.matching,
+            mismatched: self.mismatched,
+            not_detected: self.not_detected,
+            no_extension: self.no_extension,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Summary {
+    scanned: usize,
+    changed: usize,
+    skipped: usize,
+    errors: usize,
+    backups: usize,
+    dirs_removed: usize,
+    empty_files: usize,
+    xattrs_written: usize,
+    rename_retries: usize,
+    truncated: usize,
+    types: BTreeMap<String, usize>,
+}
+
+fn main() {
+    let mut kti = Kti::parse();
+    if let Some(name) = &kti.profile {
+        kti = with_profile(&kti, name);
+    }
+    if let Err(e) = check_options(&kti) {
+        e.exit();
+    }
+    imply_options(&mut kti);
+    if kti.canonicalize_root {
+        let root = kti.path.clone().unwrap_or(PathBuf::from("."));
+        kti.path = Some(canonical_root(&root));
+        if let Some(dir) = &kti.watch {
+            kti.watch = Some(canonical_root(dir));
+        }
+    }
+
+    if let Some(shell) = kti.generate_completions {
+        let mut cmd = Kti::command();
+        let name = cmd.get_name().to_string();
+        eprintln!("Generating completion file for {shell}");
+        clap_complete::generate(shell, &mut cmd, name, &mut std::io::stdout());
+        return;
+    }
+
+    if let Some(Command::Detect { file, pretend_ext }) = &kti.command {
+        explain_decision(file, pretend_ext.as_deref(), &kti);
+        return;
+    }
+
+    if let Some(Command::Explain { file, pretend_ext }) = &kti.command {
+        explain_detection(file, pretend_ext.as_deref(), &kti);
+        return;
+    }
+
+    if let Some(Command::Compare {
+        first,
+        second,
+        hash,
+    }) = &kti.command
+    {
+        match compare_files(first, second, *hash, &kti) {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("Could not compare files.");
+                eprintln!("{}", e);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    if let Some(Command::Selftest) = &kti.command {
+        match selftest::run(kti.verbose) {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("Could not run the self-test.");
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(Command::Journal {
+        command: JournalCommand::Merge { journals, output },
+    }) = &kti.command
+    {
+        if let Err(e) = merge_journals(journals, output.as_deref()) {
+            eprintln!("Could not merge journals.");
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(manifest) = &kti.manifest {
+        match manifest::validate(manifest, &kti) {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("Could not read manifest.");
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(dir) = &kti.watch {
+        if let Err(e) = watch::watch(dir, &kti) {
+            eprintln!("Could not watch directory.");
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let root_path = kti.path.clone().unwrap_or(PathBuf::from("."));
+
+    if let Ok(exists) = fs::exists(&root_path) {
+        if !exists {
+            eprintln!("Path does not exist.")
+        }
+        let run = scan(&root_path, &kti);
+        finish_run(&kti, run);
+    } else {
+        println!("Failed reading directory")
+    }
+}
+
+/// Rejects flags that only mean something with a value of another flag, as
+/// clap can only require another flag to be given.
+fn check_options(kti: &Kti) -> Result<(), clap::Error> {
+    if kti.suffix_mode == SuffixMode::Hash && kti.on_conflict != ConflictPolicy::Rename {
+        return Err(Kti::command().error(
+            clap::error::ErrorKind::ArgumentConflict,
+            "--suffix-mode hash only applies with --on-conflict rename",
+        ));
+    }
+    Ok(())
+}
+
+/// Sets the options that --safe and the action stand for.
+fn imply_options(kti: &mut Kti) {
+    if kti.safe {
+        kti.min_confidence = Some(Confidence::High);
+        kti.validate = true;
+        kti.on_conflict = ConflictPolicy::Skip;
+    }
+    if kti.action == Action::Report || kti.apply_preview {
+        kti.dry_run = true;
+    }
+}
+
+/// Checks every file below `root_path` and carries out what was planned.
+fn scan(root_path: &Path, kti: &Kti) -> Run {
+    let mut walkdir = WalkDir::new(root_path);
+
+    if let Some(depth) = kti.max_depth {
+        walkdir = walkdir.max_depth(depth)
+    }
+
+    if kti.follow_links {
+        walkdir = walkdir.follow_links(true)
+    }
+
+    // Resuming relies on the files coming in the same order every time.
+    if kti.resume.is_some() {
+        walkdir = walkdir.sort_by_file_name()
+    }
+
+    let entries = walkdir.into_iter();
+
+    let mut run = Run::default();
+    if let Some(resume) = &kti.resume {
+        let mut resume = resume.clone();
+        if let Err(e) = resume.start(root_path) {
+            eprintln!("Could not read resume state, starting over.");
+            eprintln!("{}", e);
+        }
+        run.resume = Some(resume);
+    }
+    if let Some(cache_path) = &kti.cache {
+        match Cache::load(cache_path, kti) {
+            Ok(cache) => run.cache = Some(cache),
+            Err(e) => {
+                eprintln!("Could not read cache, detecting all files again.");
+                eprintln!("{}", e);
+                run.cache = Some(Cache::new(kti));
+            }
+        }
+    }
+    let mut entries = entries.filter_entry(|e| filter_entries(e, kti));
+    while let Some(entry_result) = entries.next() {
+        let entry = match entry_result {
+            Ok(entry) => entry,
+            Err(e) => {
+                run.summary.errors += 1;
+                if kti.group_errors {
+                    run.error(kti, "Error reading entry:".to_string(), &e);
+                } else {
+                    eprintln!("Error reading entry: {}", e);
+                }
+                continue;
+            }
+        };
+
+        if let Some(resume) = &run.resume
+            && resume.is_done(entry.path(), entry.file_type().is_dir())
+        {
+            if entry.file_type().is_dir() {
+                entries.skip_current_dir();
+            }
+            continue;
+        }
+
+        if kti.detect_bundles
+            && entry.file_type().is_dir()
+            && let Some(bundle) = bundle_type(entry.path())
+        {
+            report_bundle(entry.path(), bundle, root_path, kti, &mut run);
+            if kti.skip_bundles && entry.depth() > 0 {
+                entries.skip_current_dir();
+            }
+            continue;
+        }
+
+        if !entry.path().is_file() {
+            continue;
+        }
+
+        check_file(entry.path(), entry.depth(), root_path, kti, &mut run);
+        // A dry run changes nothing, so a later real run still has to get to
+        // every file.
+        if !kti.dry_run
+            && let Some(resume) = &mut run.resume
+            && let Err(e) = resume.checked(entry.path())
+        {
+            run.summary.errors += 1;
+            run.error(kti, "Could not save resume state.".to_string(), &e);
+        }
+        if run.aborted {
+            break;
+        }
+    }
+    if !kti.dry_run
+        && let Some(resume) = &mut run.resume
+    {
+        let result = if run.aborted {
+            resume.save()
+        } else {
+            resume.finish()
+        };
+        if let Err(e) = result {
+            run.summary.errors += 1;
+            run.error(kti, "Could not save resume state.".to_string(), &e);
+        }
+    }
+    if !run.aborted {
+        apply_planned(kti, &mut run);
+    }
+    if kti.prune_empty_dirs && !run.aborted {
+        prune_empty_dirs(root_path, kti, &mut run);
+    }
+    run
+}
+
+/// Counters and buffered output collected while checking files.
+#[derive(Debug, Default)]
+struct Run {
+    diff_counter: usize,
+    extensionless_counter: usize,
+    summary: Summary,
+    audit: Audit,
+    reports: Vec<FileReport>,
+    bundles: Vec<BundleReport>,
+    /// Detections from earlier runs, with --cache.
+    cache: Option<Cache>,
+    /// Each file's text or template output with its size, for --sort-by.
+    held_output: Vec<(u64, String)>,
+    /// Each file's row with its size, for --report-columns.
+    table_rows: Vec<(u64, Vec<String>)>,
+    grouped_errors: Vec<GroupedError>,
+    preview: Preview,
+    /// Files that took longer than --read-timeout, and files from the skip
+    /// list that were read fine with --retry-skipped.
+    timed_out: Vec<PathBuf>,
+    recovered: Vec<PathBuf>,
+    /// Undetected files by their first bytes, for --dump-unknown.
+    unknown: UnknownFiles,
+    rename_map: RenameMap,
+    collisions: CollisionLog,
+    /// Progress through the tree, with --resume.
+    resume: Option<Resume>,
+    /// The worker thread files are detected on, with --read-timeout.
+    detector: Option<TimedDetector>,
+    /// Changes held back by --two-pass until every file was checked.
+    planned: Vec<PlannedChange>,
+    /// Differences per directory, for --stats-by-dir.
+    diffs_by_dir: HashMap<PathBuf, usize>,
+    /// (current, detected) -> files, for --dedupe-report.
+    deduped: BTreeMap<(String, String), DedupedGroup>,
+    aborted: bool,
+}
+
+/// A change to a file --two-pass makes once the whole tree was checked.
+#[derive(Debug)]
+enum PlannedChange {
+    RemoveEmpty(PathBuf),
+    WriteXattr {
+        path: PathBuf,
+        extension: String,
+    },
+    Rename {
+        path: PathBuf,
+        target: PathBuf,
+        /// The file's entry in the --format json report.
+        report: Option<usize>,
+        /// The file's row in the --report-columns table.
+        row: Option<usize>,
+        /// The file's --report-template line with its size, rendered once the
+        /// file was renamed.
+        line: Option<(u64, TemplateValues)>,
+    },
+}
+
+/// What a dry run would have done with each file, for --dry-run-apply-preview.
+#[derive(Debug, Default)]
+struct Preview {
+    renamed: usize,
+    extensions_added: usize,
+    conflicts: usize,
+    left: usize,
+}
+
+#[derive(Debug, Default)]
+struct DedupedGroup {
+    count: usize,
+    different: bool,
+    examples: Vec<String>,
+}
+
+/// An error held back by --group-errors until the end of the run.
+#[derive(Debug)]
+struct GroupedError {
+    kind: &'static str,
+    message: String,
+}
+
+impl Run {
+    /// Prints an error right away, or keeps it for the end with --group-errors.
+    fn error(&mut self, kti: &Kti, context: String, error: &(dyn Error + 'static)) {
+        if kti.group_errors {
+            self.grouped_errors.push(GroupedError {
+                kind: error_kind(error),
+                message: format!("{} {}", context, error),
+            });
+        } else {
+            eprintln!("{}", context);
+            eprintln!("{}", error);
+        }
+    }
+
+    fn abort(&mut self) {
+        eprintln!(
+            "Aborting after {} files checked and {} renamed.",
+            self.summary.scanned, self.summary.changed
+        );
+        self.aborted = true;
+    }
+}
+
+fn check_file(path: &Path, depth: usize, root: &Path, kti: &Kti, run: &mut Run) {
+    run.summary.scanned += 1;
+
+    // Zero-byte files are mostly what's left of failed downloads.
+    let empty = kti.on_empty.is_some() && fs::metadata(path).is_ok_and(|m| m.len() == 0);
+    if empty {
+        run.summary.empty_files += 1;
+        match kti.on_empty {
+            Some(EmptyPolicy::Skip) => {
+                run.summary.skipped += 1;
+                return;
+            }
+            Some(EmptyPolicy::Delete) if kti.two_pass && !kti.dry_run => {
+                if uses_text_report(kti) && !kti.silent {
+                    println!("Planned: remove empty file {:?}", path);
+                }
+                run.planned
+                    .push(PlannedChange::RemoveEmpty(path.to_path_buf()));
+                return;
+            }
+            Some(EmptyPolicy::Delete) => {
+                remove_empty_file(path, kti, run);
+                return;
+            }
+            _ => {}
+        }
+    }
+
+    let current = path.extension().map(display_os);
+    let current_extension: String = match &current {
+        Some(ext) => ext.clone(),
+        None => {
+            if kti.colored {
+                "No extension".yellow().to_string()
+            } else {
+                "No extension".to_string()
+            }
+        }
+    };
+
+    let mut detected = None;
+    let mut confidence = None;
+    let mut report_only = false;
+    let mut exif = None;
+    let mut variant = None;
+    let mut archive_type = None;
+    let mut truncated = false;
+    let mut alternatives = Vec::new();
+    let mut error = None;
+    let detection = match (&mut run.cache, kti.read_timeout) {
+        (Some(cache), _) => cache.detect(path, kti),
+        (None, Some(timeout)) => run
+            .detector
+            .get_or_insert_with(|| TimedDetector::new(kti))
+            .detect(path, timeout),
+        (None, None) => get_correct_extension(path, kti),
+    };
+    if let Some(skip_list) = &kti.skip_list
+        && detection.is_ok()
+        && skip_list.contains(path)
+    {
+        run.recovered.push(path.to_path_buf());
+    }
+    let detected_extension: String = match detection {
+        Ok(Some(detection)) => {
+            archive_type = Some(detection.extension.clone());
+            let ext = canonical_extension(kti, detection.extension);
+            *run.summary.types.entry(ext.clone()).or_default() += 1;
+            detected = Some(ext.clone());
+            confidence = Some(detection.confidence);
+            report_only = detection.report_only;
+            exif = detection.exif;
+            variant = detection.variant;
+            truncated = detection.truncated;
+            alternatives = detection.alternatives;
+            if truncated {
+                run.summary.truncated += 1;
+            }
+            ext
+        }
+        Ok(None) => {
+            let label = if empty { "Empty file" } else { "Not detected" };
+            if kti.colored {
+                label.yellow().to_string()
+            } else {
+                label.to_string()
+            }
+        }
+        Err(e) if is_timeout(e.as_ref()) => {
+            eprintln!(
+                "Skipping {}: reading it timed out",
+                display_os(path.as_os_str())
+            );
+            run.summary.skipped += 1;
+            run.timed_out.push(path.to_path_buf());
+            return;
+        }
+        Err(e) => {
+            // The walk only lists the file, it can still be removed or locked
+            // before kti gets to open it. That is not worth an error.
+            if let Some(reason) = vanished_reason(e.as_ref()) {
+                if kti.group_errors {
+                    let context = format!("Skipping {}:", display_os(path.as_os_str()));
+                    run.error(kti, context, e.as_ref());
+                } else {
+                    eprintln!("Skipping {}: {}", display_os(path.as_os_str()), reason);
+                }
+                run.summary.skipped += 1;
+                return;
+            }
+            run.summary.errors += 1;
+            if kti.group_errors {
+                let context = format!("Could not read {}:", display_os(path.as_os_str()));
+                run.error(kti, context, e.as_ref());
+            }
+            error = Some(e.to_string());
+            e.to_string()
+        }
+    };
+
+    let file_name = path.file_name().unwrap_or_default();
+
+    let mut contents = None;
+    if kti.scan_archives
+        && let Some(archive_type) = &archive_type
+    {
+        match scan_archive(path, archive_type, kti) {
+            Ok(scanned) => contents = scanned,
+            Err(e) => {
+                run.summary.errors += 1;
+                let context = format!("Could not look inside {:?}.", path);
+                run.error(kti, context, e.as_ref());
+            }
+        }
+    }
+
+    // Files with a binary signature aren't text, the others are only given a
+    // charset if they look like text.
+    let mut charset = None;
+    if kti.detect_charset && error.is_none() && confidence != Some(Confidence::High) {
+        match guess_charset(path) {
+            Ok(guess) => charset = guess,
+            Err(e) => {
+                run.summary.errors += 1;
+                let context = format!("Could not guess the charset of {:?}.", path);
+                run.error(kti, context, &e);
+            }
+        }
+    }
+
+    if kti.dump_unknown.is_some()
+        && detected.is_none()
+        && error.is_none()
+        && let Err(e) = run
+            .unknown
+            .add(path, display_path(path, root, kti), kti.dump_bytes)
+    {
+        run.summary.errors += 1;
+        let context = format!("Could not read the start of {:?}.", path);
+        run.error(kti, context, &e);
+    }
+
+    let mut different = error.is_none()
+        && different_extensions(&current_extension, &detected_extension)
+        && !is_tied_candidate(kti, current.as_deref(), &alternatives);
+    if let Some(allowed) = &kti.strict_extension_set
+        && let Some(detected) = &detected
+        && !allowed.contains(detected)
+    {
+        report_only = true;
+    }
+    let mut rename = different && !report_only;
+    if different && path.extension().is_none() {
+        match kti.on_no_extension {
+            NoExtensionPolicy::Skip => {
+                different = false;
+                rename = false;
+            }
+            NoExtensionPolicy::Report => rename = false,
+            NoExtensionPolicy::Add => {}
+        }
+    }
+    if different
+        && let Some(rules) = &kti.per_extension_action
+        && let Some(extension) = path.extension()
+    {
+        match rules.action_for(&display_os(extension)) {
+            Some(RuleAction::Skip) => {
+                different = false;
+                rename = false;
+            }
+            Some(RuleAction::Report) => rename = false,
+            Some(RuleAction::Rename) | None => {}
+        }
+    }
+    // With --on-empty report, empty files are flagged but have nothing to
+    // rename them to.
+    if empty {
+        different = true;
+        rename = false;
+    }
+    if different {
+        run.diff_counter += 1;
+        if kti.stats_by_dir.is_some() {
+            let parent = path.parent().unwrap_or(Path::new(""));
+            let dir = if kti.relative_paths && parent == root {
+                ".".to_string()
+            } else {
+                display_path(parent, root, kti)
+            };
+            *run.diffs_by_dir.entry(PathBuf::from(dir)).or_default() += 1;
+        }
+        if path.extension().is_none() {
+            run.extensionless_counter += 1;
+        }
+    }
+
+    // What gets printed for this file. It goes out right away, unless
+    // --sort-by holds it back until the end of the run.
+    let mut held = String::new();
+
+    let mut report = FileReport {
+        path: display_path(path, root, kti),
+        name: display_os(file_name),
+        current,
+        detected,
+        confidence,
+        report_only,
+        different,
+        alternatives,
+        truncated,
+        empty,
+        exif,
+        variant,
+        contents,
+        charset,
+        size: (kti.report_size || kti.sort_by == Some(SortKey::Size) || has_column(kti, "size"))
+            .then(|| fs::metadata(path).map(|metadata| metadata.len()).ok())
+            .flatten(),
+        modified: (kti.report_utc_time || has_column(kti, "mtime")).then(|| modified_utc(path)),
+        error,
+        renamed_to: None,
+    };
+
+    if kti.audit {
+        if report.detected.is_none() {
+            run.audit.not_detected += 1;
+        } else if report.current.is_none() {
+            run.audit.no_extension += 1;
+        } else if different {
+            run.audit.mismatched += 1;
+        } else {
+            run.audit.matching += 1;
+        }
+        if different && kti.format == Format::Ndjson {
+            print_ndjson(&NdjsonLine::File(&report));
+        } else if different {
+            run.audit.mismatches.push(report);
+        }
+        return;
+    }
+
+    if kti.dedupe_report {
+        if !kti.silent && (!kti.only_different || different) {
+            let key = (
+                report.current.clone().unwrap_or("No extension".to_string()),
+                report
+                    .detected
+                    .clone()
+                    .unwrap_or("Not detected".to_string()),
+            );
+            let group = run.deduped.entry(key).or_default();
+            group.count += 1;
+            group.different = different;
+            if group.examples.len() < kti.examples {
+                group.examples.push(report.path.clone());
+            }
+        }
+    } else if uses_text_report(kti) {
+        let mut notes = Vec::new();
+        match confidence {
+            Some(Confidence::Low) => notes.push("low confidence"),
+            Some(Confidence::Medium) => notes.push("medium confidence"),
+            _ => {}
+        }
+        if report.truncated {
+            notes.push("truncated");
+        }
+        if report_only {
+            notes.push("not renamed");
+        }
+        let detected_display = if notes.is_empty() {
+            detected_extension.clone()
+        } else {
+            format!("{} ({})", detected_extension, notes.join(", "))
+        };
+        if !kti.silent && (!kti.only_different || different) {
+            held.push_str(&format_report(
+                &report.name,
+                &report.path,
+                kti,
+                &current_extension,
+                &detected_display,
+                different,
+            ));
+            if kti.show_mtime {
+                let modified = modified_utc(path);
+                held.push_str(&format!(
+                    "Modified: {}\n",
+                    modified.as_deref().unwrap_or("unknown")
+                ));
+            }
+            if kti.report_size
+                && let Some(size) = report.size
+            {
+                held.push_str(&format!("Size: {size} bytes\n"));
+            }
+            if kti.verbose
+                && let Some(variant) = &report.variant
+            {
+                held.push_str(&format!("Variant: {variant}\n"));
+            }
+            if kti.verbose
+                && let Some(charset) = report.charset
+            {
+                held.push_str(&format!("Charset: {charset}\n"));
+            }
+            if kti.verbose
+                && let Some(contents) = &report.contents
+            {
+                held.push_str(&format_contents(contents));
+            }
+            if kti.verbose
+                && let Some(exif) = &report.exif
+            {
+                held.push_str(&format_exif(exif));
+            }
+        }
+    }
+    flush_output(kti, &mut held);
+
+    if kti
+        .rename_max_depth
+        .is_some_and(|max_depth| depth > max_depth)
+    {
+        rename = false;
+    }
+
+    // Files that already have an extension are only reported with add-ext.
+    let touches_file = kti.action != Action::AddExt || path.extension().is_none();
+    if !touches_file {
+        rename = false;
+    }
+
+    if rename && let Some(mode) = kti.write_xattr {
+        if kti.dry_run {
+            run.summary.xattrs_written += 1;
+        } else if kti.two_pass {
+            run.planned.push(PlannedChange::WriteXattr {
+                path: path.to_path_buf(),
+                extension: detected_extension.clone(),
+            });
+        } else {
+            write_xattr(path, &detected_extension, kti, run);
+        }
+        if mode == XattrMode::Only {
+            rename = false;
+        }
+    }
+
+    let target = if rename {
+        let mut updated_path = match (kti.action, &kti.dest) {
+            (Action::Copy | Action::Move, Some(dest)) => destination_path(path, root, dest),
+            _ => path.to_path_buf(),
+        };
+        let current = path.extension().and_then(|ext| ext.to_str());
+        updated_path.set_extension(apply_ext_case(detected_extension, current, kti.ext_case));
+        if kti.lowercase_name {
+            updated_path = lowercase_file_name(&updated_path);
+        }
+        if let Some(form) = kti.normalize_unicode {
+            updated_path = normalize_file_stem(&updated_path, form);
+        }
+        if let Some(replacement) = &kti.sanitize {
+            updated_path = sanitize_file_stem(&updated_path, replacement);
+        }
+        Some(updated_path)
+    } else if kti.fix_double_extensions
+        && touches_file
+        && report.detected.is_some()
+        && kti
+            .rename_max_depth
+            .is_none_or(|max_depth| depth <= max_depth)
+    {
+        collapse_double_extension(path)
+    } else {
+        None
+    };
+
+    let wanted_change = target.is_some();
+    let target = target.and_then(|updated_path| resolve_target(path, &updated_path, kti, run));
+
+    let mut changed = false;
+    let mut planned = None;
+    match target {
+        None => {
+            run.summary.skipped += 1;
+            if wanted_change {
+                run.preview.conflicts += 1;
+            } else {
+                run.preview.left += 1;
+            }
+        }
+        Some(updated_path) if kti.dry_run => {
+            run.summary.changed += 1;
+            changed = true;
+            run.rename_map.add(path, &updated_path);
+            if path.extension().is_none() {
+                run.preview.extensions_added += 1;
+            } else {
+                run.preview.renamed += 1;
+            }
+        }
+        Some(updated_path) if kti.two_pass => {
+            if uses_text_report(kti) && !kti.dedupe_report {
+                held.push_str(&format!("Planned: {:?} -> {:?}\n", path, updated_path));
+                flush_output(kti, &mut held);
+            }
+            planned = Some(updated_path);
+        }
+        Some(updated_path) => {
+            if rename_file(path, &updated_path, kti, run) {
+                run.summary.changed += 1;
+                run.rename_map.add(path, &updated_path);
+                changed = true;
+                if uses_text_report(kti) && !kti.dedupe_report {
+                    held.push_str(&format!("{:?} -> {:?}\n", path, updated_path));
+                    flush_output(kti, &mut held);
+                }
+                report.renamed_to = Some(display_os(updated_path.as_os_str()));
+            }
+        }
+    }
+
+    let size = report.size.unwrap_or(0);
+    let mut report_index = None;
+    let mut row_index = None;
+    let mut line = None;
+    if !kti.silent && (!kti.only_different || different) {
+        if let Some(template) = &kti.report_template {
+            let values = TemplateValues {
+                path: report.path.clone(),
+                name: report.name.clone(),
+                current: report.current.clone().unwrap_or_default(),
+                detected: report.detected.clone().unwrap_or_default(),
+                changed,
+                stem: display_os(path.file_stem().unwrap_or_default()),
+            };
+            if planned.is_some() {
+                line = Some((size, values));
+            } else {
+                held.push_str(&template.render(&values));
+                flush_output(kti, &mut held);
+            }
+        } else if let Some(columns) = &kti.report_columns {
+            let row = columns.row(&ColumnValues {
+                path: &report.path,
+                name: &report.name,
+                current: report.current.as_deref(),
+                detected: report.detected.as_deref(),
+                size: report.size,
+                mtime: report.modified.as_ref().and_then(|m| m.as_deref()),
+                confidence: report.confidence,
+                changed,
+            });
+            run.table_rows.push((size, row));
+            row_index = Some(run.table_rows.len() - 1);
+        } else if kti.format == Format::Ndjson && kti.sort_by.is_none() && !kti.two_pass {
+            print_ndjson(&NdjsonLine::File(&report));
+        } else if kti.format != Format::Text {
+            // --sort-by and --two-pass only know the order or the new name of
+            // a file at the end, so ndjson lines wait for them too.
+            run.reports.push(report);
+            report_index = Some(run.reports.len() - 1);
+        }
+    }
+    if !held.is_empty() {
+        run.held_output.push((size, held));
+    }
+    if let Some(target) = planned {
+        run.planned.push(PlannedChange::Rename {
+            path: path.to_path_buf(),
+            target,
+            report: report_index,
+            row: row_index,
+            line,
+        });
+    }
+}
+
+fn report_bundle(path: &Path, bundle: &'static str, root: &Path, kti: &Kti, run: &mut Run) {
+    let report = BundleReport {
+        path: display_path(path, root, kti),
+        bundle,
+    };
+    if kti.format == Format::Ndjson {
+        print_ndjson(&NdjsonLine::Bundle(&report));
+    } else if kti.format == Format::Json {
+        run.bundles.push(report);
+    } else if uses_text_report(kti) && !kti.silent {
+        println!("Bundle {}: {}", report.path, report.bundle);
+    }
+}
+
+/// Where `path` goes when `target` may already be taken, following
+/// --on-conflict and --suffix-mode. Returns `None` when the file is left alone.
+fn resolve_target(path: &Path, target: &Path, kti: &Kti, run: &mut Run) -> Option<PathBuf> {
+    if kti.on_conflict == ConflictPolicy::Rename && kti.suffix_mode == SuffixMode::Hash {
+        return match resolve_conflict_by_hash(path, target) {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                run.summary.errors += 1;
+                let context = format!("Could not hash {:?}.", path);
+                run.error(kti, context, &e);
+                None
+            }
+        };
+    }
+    let resolved = resolve_conflict(target, kti.on_conflict);
+    if resolved.is_none() {
+        skip_conflict(path, target, kti, run);
+    }
+    resolved
+}
+
+fn skip_conflict(path: &Path, target: &Path, kti: &Kti, run: &mut Run) {
+    eprintln!("Skipping {:?}: {:?} already exists.", path, target);
+    if kti.collision_log.is_some()
+        && let Err(e) = run.collisions.add(path, target, kti.collision_log_hash)
+    {
+        run.summary.errors += 1;
+        let context = format!("Could not compare {:?} with {:?}.", path, target);
+        run.error(kti, context, &e);
+    }
+}
+
+fn write_xattr(path: &Path, extension: &str, kti: &Kti, run: &mut Run) {
+    match write_detected_xattr(path, extension) {
+        Ok(()) => run.summary.xattrs_written += 1,
+        Err(e) => {
+            run.summary.errors += 1;
+            let context = format!("Could not write the detected type to {:?}.", path);
+            run.error(kti, context, &e);
+        }
+    }
+}
+
+/// Makes the changes --two-pass held back, in the order the files were
+/// checked. A name another planned rename took in the meantime is resolved
+/// again with --on-conflict and --suffix-mode. Report lines that show whether
+/// a file was changed are only finished here.
+fn apply_planned(kti: &Kti, run: &mut Run) {
+    for change in std::mem::take(&mut run.planned) {
+        match change {
+            PlannedChange::RemoveEmpty(path) => remove_empty_file(&path, kti, run),
+            PlannedChange::WriteXattr { path, extension } => {
+                write_xattr(&path, &extension, kti, run)
+            }
+            PlannedChange::Rename {
+                path,
+                target,
+                report,
+                row,
+                line,
+            } => {
+                let renamed = match resolve_target(&path, &target, kti, run) {
+                    Some(target) if rename_file(&path, &target, kti, run) => {
+                        run.summary.changed += 1;
+                        run.rename_map.add(&path, &target);
+                        if uses_text_report(kti) && !kti.dedupe_report {
+                            let mut held = format!("{:?} -> {:?}\n", path, target);
+                            flush_output(kti, &mut held);
+                            if !held.is_empty() {
+                                run.held_output.push((0, held));
+                            }
+                        }
+                        if let Some(index) = report {
+                            run.reports[index].renamed_to = Some(display_os(target.as_os_str()));
+                        }
+                        true
+                    }
+                    Some(_) => false,
+                    None => {
+                        run.summary.skipped += 1;
+                        false
+                    }
+                };
+                if let (Some(index), Some(columns)) = (row, &kti.report_columns) {
+                    columns.set_changed(&mut run.table_rows[index].1, renamed);
+                }
+                if let (Some((size, mut values)), Some(template)) = (line, &kti.report_template) {
+                    values.changed = renamed;
+                    let mut held = template.render(&values);
+                    flush_output(kti, &mut held);
+                    if !held.is_empty() {
+                        run.held_output.push((size, held));
+                    }
+                }
+            }
+        }
+        if run.aborted {
+            break;
+        }
+    }
+}
+
+fn format_contents(contents: &Contents) -> String {
+    let mut output = String::new();
+    for member in &contents.members {
+        let detected = member.detected.as_deref().unwrap_or("not detected");
+        output.push_str(&format!("Contains: {} ({detected})\n", member.name));
+    }
+    if contents.incomplete {
+        output.push_str("Contains: more files that weren't checked\n");
+    }
+    output
+}
+
+// Prints what was collected for a file so far, unless --sort-by wants all of
+// it at the end.
+fn flush_output(kti: &Kti, held: &mut String) {
+    if kti.sort_by.is_none() {
+        print!("{held}");
+        held.clear();
+    }
+}
+
+/// Renames a file, or copies or moves it for --action, backing it up first
+/// and running the hook afterwards when asked to. Returns whether the file was
+/// renamed.
+fn rename_file(path: &Path, updated_path: &Path, kti: &Kti, run: &mut Run) -> bool {
+    // A copy leaves the original as it is, so there's nothing to back up.
+    if kti.action != Action::Copy
+        && let Some(suffix) = &kti.backup
+    {
+        match create_backup(path, suffix, kti.on_conflict) {
+            Ok(Some(_)) => run.summary.backups += 1,
+            Ok(None) => {
+                eprintln!("Skipping {:?}: its backup already exists.", path);
+                run.summary.skipped += 1;
+                return false;
+            }
+            Err(e) => {
+                run.summary.errors += 1;
+                let context = format!("Could not back up {:?}.", path);
+                run.error(kti, context, e.as_ref());
+                if kti.fail_fast {
+                    run.abort();
+                }
+                return false;
+            }
+        }
+    }
+
+    if let Err(e) = apply_action_with_retry(path, updated_path, kti, run) {
+        run.summary.errors += 1;
+        let verb = match kti.action {
+            Action::Copy => "copy",
+            Action::Move => "move",
+            _ => "rename",
+        };
+        let context = format!("Could not {verb} {:?}.", path);
+        run.error(kti, context, &e);
+        if kti.fail_fast {
+            run.abort();
+        }
+        return false;
+    }
+
+    if kti.action != Action::Copy
+        && let Some(cache) = &mut run.cache
+    {
+        cache.renamed(path, updated_path);
+    }
+
+    if let Some(journal) = &kti.journal
+        && let Err(e) = journal::record(journal, path, updated_path)
+    {
+        run.summary.errors += 1;
+        let context = format!("Could not add {:?} to the journal.", updated_path);
+        run.error(kti, context, &e);
+        if kti.fail_fast {
+            run.abort();
+        }
+    }
+
+    if let Some(hook) = &kti.rename_hook
+        && let Err(e) = run_rename_hook(hook, path, updated_path)
+    {
+        run.summary.errors += 1;
+        let context = format!("Rename hook failed for {:?}.", updated_path);
+        run.error(kti, context, e.as_ref());
+        if kti.fail_fast {
+            run.abort();
+        }
+    }
+    true
+}
+
+// Waits 100ms before the first retry and twice as long before each next one.
+const RENAME_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Runs `apply_action`, retrying errors that may go away on their own as often
+/// as --rename-retry allows.
+fn apply_action_with_retry(
+    path: &Path,
+    updated_path: &Path,
+    kti: &Kti,
+    run: &mut Run,
+) -> std::io::Result<()> {
+    retry_transient(kti, run, || apply_action(path, updated_path, kti))
+}
+
+fn retry_transient(
+    kti: &Kti,
+    run: &mut Run,
+    mut attempt: impl FnMut() -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let mut delay = RENAME_RETRY_DELAY;
+    let mut retried = false;
+    let mut attempts_left = kti.rename_retry.unwrap_or(0);
+    loop {
+        match attempt() {
+            Err(e) if attempts_left > 0 && is_transient(&e) => {
+                attempts_left -= 1;
+                if !retried {
+                    retried = true;
+                    run.summary.rename_retries += 1;
+                }
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_transient(error: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(
+        error.kind(),
+        ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+            | ErrorKind::TimedOut
+            | ErrorKind::ResourceBusy
+            | ErrorKind::StaleNetworkFileHandle
+    )
+}
+
+fn apply_action(path: &Path, updated_path: &Path, kti: &Kti) -> std::io::Result<()> {
+    if matches!(kti.action, Action::Copy | Action::Move)
+        && let Some(parent) = updated_path.parent()
+    {
+        fs::create_dir_all(parent)?;
+    }
+    match kti.action {
+        Action::Copy => fs::copy(path, updated_path).map(|_| ()),
+        Action::Move => move_file(path, updated_path),
+        _ if kti.concurrency_safe => rename_via_temp(path, updated_path, kti.on_conflict),
+        _ => fs::rename(path, updated_path),
+    }
+}
+
+fn canonical_extension(kti: &Kti, extension: String) -> String {
+    match &kti.canonical {
+        Some(map) => map.apply(extension),
+        None => extension,
+    }
+}
+
+fn error_kind(error: &(dyn Error + 'static)) -> &'static str {
+    let io_error = match error.downcast_ref::<walkdir::Error>() {
+        Some(walk_error) => walk_error.io_error(),
+        None => error.downcast_ref::<std::io::Error>(),
+    };
+    match io_error.map(|e| e.kind()) {
+        Some(std::io::ErrorKind::NotFound) => "not found",
+        Some(std::io::ErrorKind::PermissionDenied) => "permission denied",
+        Some(std::io::ErrorKind::CrossesDevices) => "cross-device",
+        Some(std::io::ErrorKind::ReadOnlyFilesystem) => "read-only filesystem",
+        Some(std::io::ErrorKind::StorageFull) => "storage full",
+        Some(_) => "other I/O error",
+        None => "other",
+    }
+}
+
+fn print_grouped_errors(errors: &[GroupedError]) {
+    if errors.is_empty() {
+        return;
+    }
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    eprintln!();
+    for error in errors {
+        *counts.entry(error.kind).or_default() += 1;
+        eprintln!("{}", error.message);
+    }
+    eprintln!();
+    eprintln!("Errors: {}", errors.len());
+    for (kind, count) in counts {
+        eprintln!("  {}: {}", kind, count);
+    }
+}
+
+fn is_timeout(error: &(dyn Error + 'static)) -> bool {
+    error
+        .downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::TimedOut)
+}
+
+fn vanished_reason(error: &(dyn Error + 'static)) -> Option<&'static str> {
+    match error.downcast_ref::<std::io::Error>()?.kind() {
+        std::io::ErrorKind::NotFound => Some("the file no longer exists"),
+        std::io::ErrorKind::PermissionDenied => Some("permission denied"),
+        _ => None,
+    }
+}
+
+/// Whether the default Path/Name/Current/Detected blocks are printed.
+fn uses_text_report(kti: &Kti) -> bool {
+    kti.format == Format::Text && kti.report_template.is_none() && kti.report_columns.is_none()
+}
+
+fn has_column(kti: &Kti, column: &str) -> bool {
+    kti.report_columns
+        .as_ref()
+        .is_some_and(|columns| columns.contains(column))
+}
+
+/// Runs the user's hook for a finished rename. The command is started directly,
+/// not through a shell, as `CMD <old path> <new path>`, and both paths are also
+/// passed in the KTI_OLD_PATH and KTI_NEW_PATH environment variables.
+fn run_rename_hook(hook: &Path, old: &Path, new: &Path) -> Result<(), Box<dyn Error>> {
+    let status = std::process::Command::new(hook)
+        .arg(old)
+        .arg(new)
+        .env("KTI_OLD_PATH", old)
+        .env("KTI_NEW_PATH", new)
+        .status()?;
+    if !status.success() {
+        return Err(format!("{} exited with {}", hook.display(), status).into());
+    }
+    Ok(())
+}
+
+fn merge_journals(journals: &[PathBuf], output: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let merged = journal::merge(journals)?;
+    for warning in &merged.warnings {
+        eprintln!("Warning: {warning}");
+    }
+    match output {
+        Some(output) => journal::write_entries(&mut fs::File::create(output)?, &merged.renames)?,
+        None => journal::write_entries(&mut std::io::stdout().lock(), &merged.renames)?,
+    }
+    Ok(())
+}
+
+/// Parses the command line again with the flags of profile `name` in front of
+/// it. Repeated flags override each other, so the ones typed by the user win.
+fn with_profile(kti: &Kti, name: &str) -> Kti {
+    let Some(config) = kti.config.clone().or_else(profile::default_config_path) else {
+        eprintln!("Could not find a config file for profile {name:?}, pass one with --config.");
+        std::process::exit(1);
+    };
+    let args = match profile::profile_args(&config, name) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("Could not load profile {name:?}: {e}");
+            std::process::exit(1);
+        }
+    };
+    parse_with_profile(args, std::env::args_os()).unwrap_or_else(|e| e.exit())
+}
+
+/// Parses `command_line` with the flags of a profile put in front of it. Only
+/// then may a flag be given twice, so one on the command line replaces the
+/// profile's. Otherwise giving a flag twice stays an error.
+fn parse_with_profile(
+    profile: Vec<String>,
+    command_line: impl IntoIterator<Item = std::ffi::OsString>,
+) -> Result<Kti, clap::Error> {
+    let mut command_line: Vec<std::ffi::OsString> = command_line.into_iter().collect();
+    command_line.splice(1..1, profile.into_iter().map(Into::into));
+    let mut matches = Kti::command()
+        .args_override_self(true)
+        .try_get_matches_from(command_line)?;
+    Kti::from_arg_matches_mut(&mut matches)
+}
+
+/// Removes a zero-byte file for --on-empty delete, or only says it would with
+/// --dry-run.
+fn remove_empty_file(path: &Path, kti: &Kti, run: &mut Run) {
+    if !kti.dry_run
+        && let Err(e) = fs::remove_file(path)
+    {
+        run.summary.empty_files -= 1;
+        run.summary.errors += 1;
+        let context = format!("Could not remove {:?}.", path);
+        run.error(kti, context, &e);
+        if kti.fail_fast {
+            run.abort();
+        }
+        return;
+    }
+    if uses_text_report(kti) && !kti.silent {
+        let action = if kti.dry_run {
+            "Would remove"
+        } else {
+            "Removed"
+        };
+        println!("{} empty file {:?}", action, path);
+    }
+}
+
+/// Removes empty directories below `root`, deepest first, so a directory
+/// that only held empty directories goes as well. The root itself is kept.
+fn prune_empty_dirs(root: &Path, kti: &Kti, run: &mut Run) {
+    let mut walkdir = WalkDir::new(root).contents_first(true);
+    if let Some(depth) = kti.max_depth {
+        walkdir = walkdir.max_depth(depth)
+    }
+
+    // With --dry-run nothing is removed, so directories that would have been
+    // are remembered to treat their parents as empty too.
+    let mut removed = HashSet::new();
+    for entry in walkdir.into_iter().filter_entry(|e| filter_entries(e, kti)) {
+        let Ok(entry) = entry else {
+            continue;
+        };
+        if entry.depth() == 0 || !entry.file_type().is_dir() || entry.path_is_symlink() {
+            continue;
+        }
+        let is_empty = match fs::read_dir(entry.path()) {
+            Ok(mut children) => children.all(|child| {
+                child
+                    .map(|child| removed.contains(&child.path()))
+                    .unwrap_or(false)
+            }),
+            Err(_) => false,
+        };
+        if !is_empty {
+            continue;
+        }
+
+        if !kti.dry_run
+            && let Err(e) = fs::remove_dir(entry.path())
+        {
+            run.summary.errors += 1;
+            let context = format!("Could not remove {:?}.", entry.path());
+            run.error(kti, context, &e);
+            continue;
+        }
+        run.summary.dirs_removed += 1;
+        if uses_text_report(kti) && !kti.silent {
+            let action = if kti.dry_run {
+                "Would remove"
+            } else {
+                "Removed"
+            };
+            println!("{} empty directory {:?}", action, entry.path());
+        }
+        removed.insert(entry.into_path());
+    }
+}
+
+fn finish_run(kti: &Kti, mut run: Run) {
+    if kti.sort_by == Some(SortKey::Size) {
+        sort_by_size(&mut run);
+    }
+    for (_, output) in &run.held_output {
+        print!("{output}");
+    }
+    if kti.audit {
+        print_audit(&run.audit, kti.format);
+    } else if kti.report_template.is_some() {
+        // The template is the whole output, so scripts don't have to strip a
+        // summary line from it.
+    } else if kti.format == Format::Ndjson {
+        for report in &run.reports {
+            print_ndjson(&NdjsonLine::File(report));
+        }
+        print_ndjson(&NdjsonLine::Summary {
+            differences: run.diff_counter,
+        });
+    } else if kti.format == Format::Json {
+        let output = JsonReport {
+            files: run.reports,
+            bundles: run.bundles,
+            differences: run.diff_counter,
+        };
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&output).unwrap_or_default()
+        );
+    } else {
+        if let Some(columns) = &kti.report_columns {
+            let rows: Vec<Vec<String>> = run.table_rows.into_iter().map(|(_, row)| row).collect();
+            print!("{}", columns.table(&rows));
+        }
+        print_deduped(&run.deduped, kti);
+        if let Some(top) = kti.stats_by_dir {
+            print_stats_by_dir(&run.diffs_by_dir, top);
+        }
+        println!(
+            "Differences found: {}",
+            summary_count(run.diff_counter, kti.warn_threshold)
+        );
+        if kti.apply_preview {
+            print_preview(&run.preview, kti.action);
+        }
+        if kti.backup.is_some() {
+            println!("Backups created: {}", run.summary.backups);
+        }
+        if kti.prune_empty_dirs {
+            println!("Empty directories removed: {}", run.summary.dirs_removed);
+        }
+        match kti.on_empty {
+            Some(EmptyPolicy::Report) => println!("Empty files: {}", run.summary.empty_files),
+            Some(EmptyPolicy::Delete) => {
+                println!("Empty files removed: {}", run.summary.empty_files)
+            }
+            _ => {}
+        }
+        if kti.validate {
+            println!("Truncated files: {}", run.summary.truncated);
+        }
+        if kti.write_xattr.is_some() {
+            println!("Attributes written: {}", run.summary.xattrs_written);
+        }
+        if kti.rename_retry.is_some() {
+            println!("Renames retried: {}", run.summary.rename_retries);
+        }
+        if kti.only_extensionless {
+            println!(
+                "Extensionless files classified: {}",
+                run.extensionless_counter
+            );
+        }
+    }
+    print_grouped_errors(&run.grouped_errors);
+    if let (Some(cache_path), Some(cache)) = (&kti.cache, &run.cache)
+        && let Err(e) = cache.save(cache_path)
+    {
+        eprintln!("Could not write cache.");
+        eprintln!("{}", e)
+    }
+    if let Some(dir) = &kti.dump_unknown
+        && let Err(e) = run.unknown.write_report(dir)
+    {
+        eprintln!("Could not write the report of unknown files.");
+        eprintln!("{}", e)
+    }
+    if let Some(map_path) = &kti.rename_map
+        && let Err(e) = run.rename_map.write(map_path, kti.rename_map_format)
+    {
+        eprintln!("Could not write rename map.");
+        eprintln!("{}", e)
+    }
+    if let Some(log_path) = &kti.collision_log
+        && let Err(e) = run.collisions.write(log_path)
+    {
+        eprintln!("Could not write collision log.");
+        eprintln!("{}", e)
+    }
+    if let Some(skip_list) = &kti.skip_list
+        && let Err(e) = skip_list.save(&run.timed_out, &run.recovered)
+    {
+        eprintln!("Could not write skip list.");
+        eprintln!("{}", e)
+    }
+    if let Some(summary_path) = &kti.summary_json
+        && let Err(e) = write_summary_json(summary_path, &run.summary)
+    {
+        eprintln!("Could not write summary.");
+        eprintln!("{}", e)
+    }
+    if exceeds_max_errors(kti, run.summary.errors) || run.aborted {
+        std::process::exit(1);
+    }
+}
+
+/// Whether more files failed than --max-errors allows, after showing the
+/// count against it.
+fn exceeds_max_errors(kti: &Kti, errors: usize) -> bool {
+    let Some(max_errors) = kti.max_errors else {
+        return false;
+    };
+    eprintln!("Errors: {} (allowed: {})", errors, max_errors);
+    errors > max_errors
+}
+
+/// Orders what --sort-by size held back with the largest files first.
+fn sort_by_size(run: &mut Run) {
+    run.held_output.sort_by_key(|(size, _)| Reverse(*size));
+    run.table_rows.sort_by_key(|(size, _)| Reverse(*size));
+    run.reports.sort_by_key(|report| Reverse(report.size));
+}
+
+/// The number of differences for the summary, colored by --warn-threshold.
+fn summary_count(count: usize, warn_threshold: Option<usize>) -> String {
+    match warn_threshold {
+        None => count.to_string(),
+        Some(_) if count == 0 => count.green().to_string(),
+        Some(threshold) if count <= threshold => count.yellow().to_string(),
+        Some(_) => count.bright_red().to_string(),
+    }
+}
+
+fn print_preview(preview: &Preview, action: Action) {
+    let verb = match action {
+        Action::Copy => "copy",
+        Action::Move => "move",
+        _ => "rename",
+    };
+    println!(
+        "Would {verb}: {}, would skip (conflict): {}, would add extension: {}, would leave: {}",
+        preview.renamed, preview.conflicts, preview.extensions_added, preview.left
+    );
+}
+
+fn print_stats_by_dir(diffs_by_dir: &HashMap<PathBuf, usize>, top: usize) {
+    let mut dirs: Vec<(&PathBuf, &usize)> = diffs_by_dir.iter().collect();
+    dirs.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+    println!();
+    println!("Directories with the most differences:");
+    for (dir, count) in dirs.into_iter().take(top) {
+        println!("{:>6}  {}", count, display_os(dir.as_os_str()));
+    }
+}
+
+fn print_deduped(groups: &BTreeMap<(String, String), DedupedGroup>, kti: &Kti) {
+    for ((current, detected), group) in groups {
+        let files = if group.count == 1 { "file" } else { "files" };
+        println!();
+        if kti.colored {
+            let current = if group.different {
+                current.bright_red().to_string()
+            } else {
+                current.bright_green().to_string()
+            };
+            println!(
+                "{} -> {}: {} {}",
+                current,
+                detected.bright_green(),
+                group.count,
+                files
+            );
+        } else {
+            println!("{} -> {}: {} {}", current, detected, group.count, files);
+        }
+        for example in &group.examples {
+            println!("  {}", example);
+        }
+        if group.count > group.examples.len() {
+            println!("  ... and {} more", group.count - group.examples.len());
+        }
+    }
+}
+
+fn format_exif(exif: &Exif) -> String {
+    let mut text = String::new();
+    if let (Some(orientation), Some(description)) = (exif.orientation, exif.describe_orientation())
+    {
+        text.push_str(&format!("Orientation: {} ({})\n", orientation, description));
+    }
+    if let Some(make) = &exif.make {
+        text.push_str(&format!("Camera make: {}\n", make));
+    }
+    text
+}
+
+fn collapse_double_extension(path: &Path) -> Option<PathBuf> {
+    let extension = path.extension()?;
+    let mut collapsed = path.to_path_buf();
+    while let Some(stem) = collapsed.file_stem()
+        && Path::new(stem).extension() == Some(extension)
+    {
+        collapsed.set_extension("");
+    }
+    (collapsed != path).then_some(collapsed)
+}
+
+/// Turns a file name or path into text for the report. Bytes that aren't
+/// valid UTF-8 are written as `\xFF` escapes instead of being replaced, so
+/// such names stay recognizable. Renaming always works on the original name.
+fn display_os(value: &OsStr) -> String {
+    let mut text = String::new();
+    for chunk in value.as_encoded_bytes().utf8_chunks() {
+        text.push_str(chunk.valid());
+        for byte in chunk.invalid() {
+            text.push_str(&format!("\\x{byte:02X}"));
+        }
+    }
+    text
+}
+
+fn display_path(path: &Path, root: &Path, kti: &Kti) -> String {
+    if let Some(base) = &kti.report_relative_to {
+        let absolute = fs::canonicalize(path).or_else(|_| std::path::absolute(path));
+        if let Ok(absolute) = absolute {
+            return match relative_to(&absolute, base) {
+                Some(relative) => display_os(relative.as_os_str()),
+                None => display_os(absolute.as_os_str()),
+            };
+        }
+    }
+    if kti.relative_paths {
+        match path.strip_prefix(root) {
+            Ok(relative) if !relative.as_os_str().is_empty() => {
+                return display_os(relative.as_os_str());
+            }
+            _ => {
+                if let Some(name) = path.file_name() {
+                    return display_os(name);
+                }
+            }
+        }
+    }
+    if kti.absolute
+        && let Ok(absolute) = fs::canonicalize(path)
+    {
+        return display_os(absolute.as_os_str());
+    }
+    display_os(path.as_os_str())
+}
+
+/// Resolves the scan root for --canonicalize-root. A root that can't be
+/// resolved, like a dangling symlink, is made absolute without following
+/// links, so the run still reports it the same way each time.
+fn canonical_root(root: &Path) -> PathBuf {
+    match fs::canonicalize(root) {
+        Ok(canonical) => canonical,
+        Err(e) => {
+            eprintln!("Could not resolve {:?}, using it as given: {}", root, e);
+            std::path::absolute(root).unwrap_or(root.to_path_buf())
+        }
+    }
+}
+
+fn parse_report_base(value: &str) -> Result<PathBuf, String> {
+    fs::canonicalize(value).map_err(|e| format!("could not resolve '{value}': {e}"))
+}
+
+/// Writes the absolute `path` relative to the absolute `base`, going up with
+/// `..` where `base` isn't one of its parents. Returns `None` for paths that
+/// share no root with `base`, like ones on another drive.
+fn relative_to(path: &Path, base: &Path) -> Option<PathBuf> {
+    let mut path_components = path.components().peekable();
+    let mut base_components = base.components().peekable();
+    if path_components.peek() != base_components.peek() {
+        return None;
+    }
+    while let (Some(a), Some(b)) = (path_components.peek(), base_components.peek())
+        && a == b
+    {
+        path_components.next();
+        base_components.next();
+    }
+    let mut relative: PathBuf = base_components.map(|_| "..").collect();
+    relative.extend(path_components);
+    if relative.as_os_str().is_empty() {
+        relative.push(".");
+    }
+    Some(relative)
+}
+
+// Prints a line of --format ndjson output right away, so whatever reads it
+// doesn't wait for the run to end.
+fn print_ndjson(line: &NdjsonLine) {
+    let _ = write_ndjson(&mut std::io::stdout().lock(), line);
+}
+
+fn write_ndjson(out: &mut impl Write, line: &NdjsonLine) -> std::io::Result<()> {
+    writeln!(out, "{}", serde_json::to_string(line).unwrap_or_default())?;
+    out.flush()
+}
+
+fn print_audit(audit: &Audit, format: Format) {
+    match format {
+        Format::Json => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(audit).unwrap_or_default()
+            );
+            return;
+        }
+        Format::Ndjson => {
+            // The mismatches were printed as they were found.
+            print_ndjson(&audit.ndjson_summary());
+            return;
+        }
+        Format::Text => {}
+    }
+    println!("Matching:     {}", audit.matching);
+    println!("Mismatched:   {}", audit.mismatched);
+    println!("Not detected: {}", audit.not_detected);
+    println!("No extension: {}", audit.no_extension);
+    if !audit.mismatches.is_empty() {
+        println!();
+        println!("Mismatches:");
+        for report in &audit.mismatches {
+            println!(
+                "{}: {} -> {}",
+                report.path,
+                report.current.as_deref().unwrap_or("No extension"),
+                report.detected.as_deref().unwrap_or("Not detected")
+            );
+        }
+    }
+}
+
+fn write_summary_json(path: &Path, summary: &Summary) -> Result<(), Box<dyn Error>> {
+    let file = fs::File::create(path)?;
+    serde_json::to_writer_pretty(file, summary)?;
+    Ok(())
+}
+
+fn format_report(
+    name: &str,
+    path: &str,
+    kti: &Kti,
+    current: &str,
+    detected: &str,
+    different: bool,
+) -> String {
+    if kti.colored {
+        let current = if different {
+            current.bright_red().to_string()
+        } else {
+            current.bright_green().to_string()
+        };
+        format!(
+            "\nPath: {}\nName: {}\nCurrent:  {}\nDetected: {}\n",
+            path.bright_green(),
+            name.bright_green(),
+            current,
+            detected.bright_green()
+        )
+    } else {
+        format!("\nPath: {path}\nName: {name}\nCurrent:  {current}\nDetected: {detected}\n")
+    }
+}
+
+fn filter_entries(entry: &DirEntry, options: &Kti) -> bool {
+    // The root was asked for explicitly, so it is never filtered out. This
+    // also keeps the default "." root from being treated as hidden.
+    if entry.depth() == 0 {
+        return true;
+    }
+    if !options.show_hidden && is_hidden(entry) {
+        return false;
+    }
+    if entry.file_type().is_dir() && is_excluded_dir(entry, options) {
+        return false;
+    }
+    if let Some(renamed) = &options.since_journal
+        && !entry.file_type().is_dir()
+        && renamed.contains(entry.path())
+    {
+        return false;
+    }
+    if let Some(skip_list) = &options.skip_list
+        && !options.retry_skipped
+        && !entry.file_type().is_dir()
+        && skip_list.contains(entry.path())
+    {
+        return false;
+    }
+    if !options.show_hidden
+        && options.check_link_targets
+        && entry.path_is_symlink()
+        && link_target_is_hidden(entry)
+    {
+        return false;
+    }
+    if options.only_extensionless
+        && !entry.file_type().is_dir()
+        && entry.path().extension().is_some()
+    {
+        return false;
+    }
+    if let Some(since) = options.since
+        && !entry.file_type().is_dir()
+        && !modified_within(entry, since)
+    {
+        return false;
+    }
+    true
+}
+
+fn modified_within(entry: &DirEntry, since: Duration) -> bool {
+    let Some(cutoff) = SystemTime::now().checked_sub(since) else {
+        return true;
+    };
+    match entry.metadata().map(|m| m.modified()) {
+        Ok(Ok(modified)) => modified >= cutoff,
+        _ => true,
+    }
+}
+
+fn modified_utc(path: &Path) -> Option<String> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    format_utc(modified)
+}
+
+/// Formats a time as ISO 8601 in UTC, like 2024-05-01T13:45:00Z.
+fn format_utc(time: SystemTime) -> Option<String> {
+    let seconds = time.duration_since(SystemTime::UNIX_EPOCH).ok()?.as_secs();
+    let (days, time_of_day) = ((seconds / 86400) as i64, seconds % 86400);
+
+    // Converts days since 1970-01-01 to a civil date, see
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    Some(format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time_of_day / 3600,
+        time_of_day % 3600 / 60,
+        time_of_day % 60
+    ))
+}
+
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("invalid duration '{value}'"))?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        "w" => 60 * 60 * 24 * 7,
+        _ => {
+            return Err(format!(
+                "unknown duration unit '{unit}', use s, m, h, d or w"
+            ));
+        }
+    };
+    Ok(Duration::from_secs(amount * seconds))
+}
+
+fn is_excluded_dir(entry: &DirEntry, options: &Kti) -> bool {
+    let root = options.path.as_deref().unwrap_or(Path::new("."));
+    let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+    options.exclude_dirs.iter().any(|excluded| {
+        let excluded = excluded.strip_prefix("./").unwrap_or(excluded);
+        if excluded.components().count() == 1 {
+            entry.file_name() == excluded.as_os_str()
+        } else {
+            relative == excluded
+        }
+    })
+}
+
+fn is_hidden(entry: &DirEntry) -> bool {
+    entry
+        .file_name()
+        .to_str()
+        .map(|s| s.starts_with("."))
+        .unwrap_or(false)
+}
+
+fn link_target_is_hidden(entry: &DirEntry) -> bool {
+    fs::canonicalize(entry.path())
+        .ok()
+        .and_then(|target| {
+            target
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| name.starts_with("."))
+        })
+        .unwrap_or(false)
+}
+
+/// Prints the detected type of both files for `kti compare` and returns
+/// whether they match, including their header hash with `hash`.
+fn compare_files(
+    first: &Path,
+    second: &Path,
+    hash: bool,
+    kti: &Kti,
+) -> Result<bool, Box<dyn Error>> {
+    let detect = |path: &Path| -> Result<Option<String>, Box<dyn Error>> {
+        Ok(get_correct_extension(path, kti)?
+            .map(|detection| canonical_extension(kti, detection.extension)))
+    };
+    let types = (detect(first)?, detect(second)?);
+    for (path, detected) in [(first, &types.0), (second, &types.1)] {
+        println!(
+            "{}: {}",
+            display_os(path.as_os_str()),
+            detected.as_deref().unwrap_or("Not detected")
+        );
+    }
+    // Two files kti knows nothing about aren't known to be the same kind.
+    let mut same = types.0.is_some() && types.0 == types.1;
+    println!("Same type: {}", if same { "yes" } else { "no" });
+    if hash {
+        let same_header = cache::fingerprint(first)? == cache::fingerprint(second)?;
+        println!("Same header: {}", if same_header { "yes" } else { "no" });
+        same &= same_header;
+    }
+    Ok(same)
+}
+
+/// How kti would treat a single file, for `kti detect` and `kti explain`.
+#[derive(Debug, Serialize)]
+struct Decision {
+    current: String,
+    detected: String,
+    confidence: Option<Confidence>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    alternatives: Vec<String>,
+    rename: bool,
+    reason: String,
+}
+
+fn decide(file: &Path, pretend_ext: Option<&str>, kti: &Kti) -> Result<Decision, Box<dyn Error>> {
+    let current = match pretend_ext {
+        Some(ext) => ext.trim_start_matches('.').to_string(),
+        None => match file.extension() {
+            Some(ext) => display_os(ext),
+            None => "No extension".to_string(),
+        },
+    };
+    let mut confidence = None;
+    let mut report_only = false;
+    let mut alternatives = Vec::new();
+    let detected = match get_correct_extension(file, kti)? {
+        Some(detection) => {
+            confidence = Some(detection.confidence);
+            report_only = detection.report_only;
+            alternatives = detection.alternatives.clone();
+            canonical_extension(kti, detection.extension)
+        }
+        None => "Not detected".to_string(),
+    };
+
+    let (mut different, mut reason) = compare_extensions(&current, &detected);
+    if different && is_tied_candidate(kti, Some(&current), &alternatives) {
+        different = false;
+        reason = "the extension is one of the types the file could be";
+    }
+    let allowed = kti
+        .strict_extension_set
+        .as_ref()
+        .is_none_or(|allowed| allowed.contains(&detected));
+    let (rename, reason) = if different && !allowed {
+        (
+            false,
+            format!("{detected} is not in the --strict-extension-set"),
+        )
+    } else if different && report_only {
+        (
+            false,
+            "the detection is too weak to rename the file".to_string(),
+        )
+    } else {
+        (different, reason.to_string())
+    };
+    Ok(Decision {
+        current,
+        detected,
+        confidence,
+        alternatives,
+        rename,
+        reason,
+    })
+}
+
+fn explain_decision(file: &Path, pretend_ext: Option<&str>, kti: &Kti) {
+    let decision = match decide(file, pretend_ext, kti) {
+        Ok(decision) => decision,
+        Err(e) => {
+            eprintln!("Could not read file.");
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    println!("Path: {}", display_os(file.as_os_str()));
+    print!("{}", format_decision(&decision));
+}
+
+fn format_decision(decision: &Decision) -> String {
+    let mut output = format!(
+        "Current:  {}\nDetected: {}\n",
+        decision.current, decision.detected
+    );
+    if let Some(confidence) = decision.confidence {
+        output.push_str(&format!("Confidence: {:?}\n", confidence));
+    }
+    if !decision.alternatives.is_empty() {
+        output.push_str(&format!(
+            "Could also be: {}\n",
+            decision.alternatives.join(", ")
+        ));
+    }
+    if decision.rename {
+        output.push_str(&format!("Would rename: {}\n", decision.reason));
+    } else {
+        output.push_str(&format!("Would keep: {}\n", decision.reason));
+    }
+    output
+}
+
+/// Every step of the detection for `kti explain`, with how kti would treat
+/// the file in the end.
+#[derive(Debug, Serialize)]
+struct Explanation {
+    path: String,
+    checks: Vec<Check>,
+    #[serde(flatten)]
+    decision: Decision,
+}
+
+fn explain_detection(file: &Path, pretend_ext: Option<&str>, kti: &Kti) {
+    let explanation = match explain(file, pretend_ext, kti) {
+        Ok(explanation) => explanation,
+        Err(e) => {
+            eprintln!("Could not read file.");
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    match kti.format {
+        Format::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&explanation).unwrap_or_default()
+        ),
+        Format::Ndjson => println!(
+            "{}",
+            serde_json::to_string(&explanation).unwrap_or_default()
+        ),
+        Format::Text => print!("{}", format_explanation(&explanation)),
+    }
+}
+
+fn explain(
+    file: &Path,
+    pretend_ext: Option<&str>,
+    kti: &Kti,
+) -> Result<Explanation, Box<dyn Error>> {
+    Ok(Explanation {
+        path: display_os(file.as_os_str()),
+        checks: trace_detection(file, kti)?,
+        decision: decide(file, pretend_ext, kti)?,
+    })
+}
+
+fn format_explanation(explanation: &Explanation) -> String {
+    let mut output = format!("Path: {}\n", explanation.path);
+    for check in &explanation.checks {
+        let at = check
+            .offset
+            .map(|offset| format!(" at {offset}"))
+            .unwrap_or_default();
+        let found = match (&check.matched, check.needs) {
+            (_, Some(needs)) => {
+                output.push_str(&format!("Check {}: not run, needs {needs}\n", check.step));
+                continue;
+            }
+            (Some(ext), None) if check.used => format!("{ext} (used)"),
+            (Some(ext), None) => ext.clone(),
+            (None, None) => "no match".to_string(),
+        };
+        output.push_str(&format!("Check {}{at}: {found}\n", check.step));
+    }
+    output.push_str(&format_decision(&explanation.decision));
+    output
+}
+
+/// With --prefer-existing-when-tie a file keeps its extension if it names one
+/// of the other types the file could be.
+fn is_tied_candidate(kti: &Kti, current: Option<&str>, alternatives: &[String]) -> bool {
+    kti.prefer_existing_when_tie
+        && current.is_some_and(|current| {
+            alternatives
+                .iter()
+                .any(|alternative| alternative.eq_ignore_ascii_case(current))
+        })
+}
+
+fn different_extensions(current: &str, detected: &str) -> bool {
+    compare_extensions(current, detected).0
+}
+
+// Most camera raw formats are TIFF files inside, and renaming them to .tif
+// would hide them from photo software.
+const TIFF_BASED: [&str; 20] = [
+    "tiff", "dng", "nef", "nrw", "arw", "sr2", "srf", "cr2", "pef", "srw", "3fr", "erf", "kdc",
+    "dcr", "fff", "iiq", "mef", "mos", "gtiff", "gtif",
+];
+
+// Office documents, Java archives, app packages and many other formats are
+// plain ZIP files that kti can't tell apart, so their extensions are kept.
+const ZIP_BASED: [&str; 27] = [
+    "docx", "xlsx", "pptx", "docm", "xlsm", "pptm", "jar", "war", "ear", "apk", "aab", "ipa",
+    "xpi", "whl", "nupkg", "vsix", "appx", "msix", "xps", "3mf", "cbz", "sketch", "key", "numbers",
+    "pages", "twbx", "pbix",
+];
+
+// Compressed tarballs, SVG images and Ableton Live sets are often named after
+// what is inside the gzip stream. Only --deep looks into the stream, for SVG
+// and, with the daw feature, Live sets, so svgz and als are kept too.
+const GZIP_BASED: [&str; 4] = ["tgz", "svgz", "als", "gzip"];
+
+// macOS installer packages and Xcode archives are XAR archives.
+const XAR_BASED: [&str; 3] = ["pkg", "mpkg", "xip"];
+
+// Matroska audio, subtitle and 3D video files, which are only told apart
+// from plain mkv and webm files with --deep.
+const MATROSKA_BASED: [&str; 4] = ["mka", "mks", "mk3d", "weba"];
+
+// Windows Media files are ASF files, which are only told apart with --deep.
+const ASF_BASED: [&str; 2] = ["wmv", "wma"];
+
+/// Decides whether a file needs renaming, along with the reason for the decision.
+fn compare_extensions(current: &str, detected: &str) -> (bool, &'static str) {
+    if detected.contains("No") || detected.contains("Err") {
+        return (false, "the file type was not detected");
+    }
+    if current.contains("No extension") {
+        return (true, "the file has no extension");
+    }
+    // Extensions are compared regardless of case, --ext-case only decides how
+    // the new one is written.
+    let current = current.to_lowercase();
+    let current = current.as_str();
+    let detected = detected.to_lowercase();
+    let detected = detected.as_str();
+    if current == "jpeg" && detected == "jpg" {
+        return (false, "jpeg is accepted for jpg");
+    }
+    if current == "png" && detected == "apng" {
+        return (false, "png is accepted for apng");
+    }
+    if current == "aif" && detected == "aiff" {
+        return (false, "aif is accepted for aiff");
+    }
+    // Windows metadata files are .NET assemblies marked as a library.
+    if current == "winmd" && detected == "dll" {
+        return (false, "winmd is accepted for dll");
+    }
+    if current == "pic" && detected == "hdr" {
+        return (false, "pic is accepted for hdr");
+    }
+    // Shapefile indexes have the same header as the shapes they index.
+    if current == "shx" && detected == "shp" {
+        return (false, "shx is accepted for shp");
+    }
+    // Windows import libraries and Rust libraries are ar archives too.
+    if (current == "lib" || current == "rlib") && detected == "a" {
+        return (false, "the extension is an ar based format");
+    }
+    // Kindle books keep the Mobipocket format under their own names.
+    if matches!(current, "azw" | "azw3" | "prc") && detected == "mobi" {
+        return (false, "the extension is a Mobipocket based format");
+    }
+    if matches!(current, "azw1" | "tpz") && detected == "azw" {
+        return (false, "the extension is used for Topaz books");
+    }
+    if current == "bundle" && detected == "unity3d" {
+        return (false, "bundle is accepted for unity3d");
+    }
+    // Certificates and keys are named after what they hold more often than
+    // after their encoding.
+    if matches!(
+        current,
+        "crt" | "cer" | "cert" | "key" | "csr" | "req" | "pub" | "p7b" | "p8"
+    ) && detected == "pem"
+    {
+        return (false, "the extension is used for PEM files");
+    }
+    if matches!(current, "crt" | "cer" | "cert" | "key" | "p7b" | "p8") && detected == "der" {
+        return (false, "the extension is used for DER files");
+    }
+    if matches!(current, "sig" | "gpg") && detected == "asc" {
+        return (false, "the extension is used for armored OpenPGP files");
+    }
+    if current == "keystore" && detected == "jks" {
+        return (false, "keystore is accepted for jks");
+    }
+    if current == "pax" && detected == "tar" {
+        return (false, "pax is accepted for tar");
+    }
+    if current == "tzst" && detected == "zst" {
+        return (false, "tzst is accepted for zst");
+    }
+    if current == "pyo" && detected == "pyc" {
+        return (false, "pyo is accepted for pyc");
+    }
+    // Lua loads bytecode just as well from a .lua file, which some programs
+    // rely on.
+    if current == "lua" && detected == "luac" {
+        return (false, "lua is accepted for luac");
+    }
+    if (detected.eq_ignore_ascii_case("tif") || detected.eq_ignore_ascii_case("tiff"))
+        && TIFF_BASED
+            .iter()
+            .any(|ext| ext.eq_ignore_ascii_case(current))
+    {
+        return (false, "the extension is a TIFF based format");
+    }
+    // Raw files told apart only by the camera make share it with other TIFF
+    // based files of that maker, like Nikon's .nrw or a Nikon scanner's .tif.
+    if RAW_MAKERS.iter().any(|(_, raw)| *raw == detected)
+        && (current == "tif" || TIFF_BASED.contains(&current))
+    {
+        return (false, "the extension is a TIFF based format");
+    }
+    // Android packages and browser extensions carry a jar manifest too.
+    if (detected.eq_ignore_ascii_case("zip") || detected.eq_ignore_ascii_case("jar"))
+        && ZIP_BASED
+            .iter()
+            .any(|ext| ext.eq_ignore_ascii_case(current))
+    {
+        return (false, "the extension is a ZIP based format");
+    }
+    if detected.eq_ignore_ascii_case("gz")
+        && GZIP_BASED
+            .iter()
+            .any(|ext| ext.eq_ignore_ascii_case(current))
+    {
+        return (false, "the extension is a gzip based format");
+    }
+    if detected.eq_ignore_ascii_case("xar")
+        && XAR_BASED
+            .iter()
+            .any(|ext| ext.eq_ignore_ascii_case(current))
+    {
+        return (false, "the extension is a XAR based format");
+    }
+    if (detected.eq_ignore_ascii_case("mkv") || detected.eq_ignore_ascii_case("webm"))
+        && MATROSKA_BASED
+            .iter()
+            .any(|ext| ext.eq_ignore_ascii_case(current))
+    {
+        return (false, "the extension is a Matroska based format");
+    }
+    if (detected.eq_ignore_ascii_case("asf")
+        && ASF_BASED
+            .iter()
+            .any(|ext| ext.eq_ignore_ascii_case(current)))
+        || (current.eq_ignore_ascii_case("asf")
+            && ASF_BASED
+                .iter()
+                .any(|ext| ext.eq_ignore_ascii_case(detected)))
+    {
+        return (false, "the extension is an ASF based format");
+    }
+    if current == detected {
+        return (false, "the extension matches the detected type");
+    }
+    (true, "the extension does not match the detected type")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::{TempDir, kti, scan};
+
+    const GIF: &[u8] = b"GIF89a\x01\0\x01\0\0\0\0;";
+
+    #[test]
+    fn extensions_are_compared_regardless_of_case() {
+        assert!(!compare_extensions("JPEG", "jpg").0);
+        assert!(!compare_extensions("jpeg", "JPG").0);
+        assert!(!compare_extensions("PYO", "pyc").0);
+        assert!(compare_extensions("PNG", "jpg").0);
+        assert!(compare_extensions("No extension", "png").0);
+    }
+
+    #[test]
+    fn keys_keep_the_names_they_are_used_under() {
+        for current in ["key", "crt", "req", "p7b", "p8"] {
+            assert!(!compare_extensions(current, "pem").0, "{current}");
+        }
+        assert!(!compare_extensions("p7b", "der").0);
+        assert!(!compare_extensions("gpg", "asc").0);
+        assert!(compare_extensions("txt", "pem").0);
+    }
+
+    #[test]
+    fn raw_files_known_by_their_make_keep_tiff_based_names() {
+        for current in ["nrw", "sr2", "srf", "tif", "TIF", "nef"] {
+            assert!(!compare_extensions(current, "nef").0, "{current}");
+            assert!(!compare_extensions(current, "arw").0, "{current}");
+        }
+        assert!(compare_extensions("jpg", "nef").0);
+        assert!(compare_extensions("nef", "cr2").0);
+    }
+
+    #[test]
+    fn gzip_based_names_are_kept() {
+        for current in ["tgz", "svgz", "als", "ALS", "gzip"] {
+            assert!(!compare_extensions(current, "gz").0, "{current}");
+        }
+        assert!(compare_extensions("txt", "gz").0);
+    }
+
+    #[test]
+    fn durations_are_parsed() {
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration("24h"), Ok(Duration::from_secs(24 * 60 * 60)));
+        assert_eq!(
+            parse_duration("7d"),
+            Ok(Duration::from_secs(7 * 24 * 60 * 60))
+        );
+        assert!(parse_duration("7").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("7y").is_err());
+    }
+
+    #[test]
+    fn since_skips_files_modified_before_the_cutoff() {
+        let dir = TempDir::new();
+        let day = Duration::from_secs(24 * 60 * 60);
+        for (name, age) in [
+            ("new.txt", Duration::ZERO),
+            ("hour.txt", day / 24),
+            ("old.txt", day * 2),
+        ] {
+            let file = fs::File::create(dir.path().join(name)).unwrap();
+            file.set_modified(SystemTime::now() - age).unwrap();
+        }
+
+        let kti = kti(&["--since", "1d", dir.path().to_str().unwrap()]);
+        let mut kept: Vec<_> = WalkDir::new(dir.path())
+            .min_depth(1)
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|entry| filter_entries(entry, &kti))
+            .map(|entry| entry.file_name().to_owned())
+            .collect();
+        kept.sort();
+        assert_eq!(kept, ["hour.txt", "new.txt"]);
+    }
+
+    /// A directory holding a gif named .txt and one without an extension.
+    fn misnamed_gifs() -> TempDir {
+        let dir = TempDir::new();
+        dir.file("a.txt", GIF);
+        dir.file("b", GIF);
+        dir
+    }
+
+    #[test]
+    fn report_is_the_default_action() {
+        let dir = misnamed_gifs();
+        let run = scan(dir.path(), &[]);
+        assert_eq!(run.diff_counter, 2);
+        assert!(dir.path().join("a.txt").exists());
+        assert!(dir.path().join("b").exists());
+        assert!(!dir.path().join("a.gif").exists());
+        assert!(!dir.path().join("b.gif").exists());
+    }
+
+    #[test]
+    fn rename_fixes_the_extensions() {
+        let dir = misnamed_gifs();
+        scan(dir.path(), &["--action", "rename"]);
+        assert!(dir.path().join("a.gif").exists());
+        assert!(dir.path().join("b.gif").exists());
+        assert!(!dir.path().join("a.txt").exists());
+        assert!(!dir.path().join("b").exists());
+    }
+
+    #[test]
+    fn copy_leaves_the_originals_in_place() {
+        let dir = misnamed_gifs();
+        let dest = TempDir::new();
+        let dest_arg = dest.path().to_str().unwrap();
+        scan(dir.path(), &["--action", "copy", "--dest", dest_arg]);
+        assert!(dest.path().join("a.gif").exists());
+        assert!(dest.path().join("b.gif").exists());
+        assert!(dir.path().join("a.txt").exists());
+        assert!(dir.path().join("b").exists());
+    }
+
+    #[test]
+    fn move_takes_the_originals_away() {
+        let dir = misnamed_gifs();
+        let dest = TempDir::new();
+        let dest_arg = dest.path().to_str().unwrap();
+        scan(dir.path(), &["--action", "move", "--dest", dest_arg]);
+        assert!(dest.path().join("a.gif").exists());
+        assert!(dest.path().join("b.gif").exists());
+        assert!(!dir.path().join("a.txt").exists());
+        assert!(!dir.path().join("b").exists());
+    }
+
+    #[test]
+    fn add_ext_only_names_extensionless_files() {
+        let dir = misnamed_gifs();
+        scan(dir.path(), &["--action", "add-ext"]);
+        assert!(dir.path().join("a.txt").exists());
+        assert!(dir.path().join("b.gif").exists());
+        assert!(!dir.path().join("a.gif").exists());
+        assert!(!dir.path().join("b").exists());
+    }
+
+    #[test]
+    fn two_pass_changes_nothing_until_every_file_was_checked() {
+        let dir = misnamed_gifs();
+        let kti = kti(&[
+            "--two-pass",
+            "--action",
+            "rename",
+            dir.path().to_str().unwrap(),
+        ]);
+        let mut run = Run::default();
+        for name in ["a.txt", "b"] {
+            check_file(&dir.path().join(name), 1, dir.path(), &kti, &mut run);
+        }
+        assert_eq!(run.planned.len(), 2);
+        assert!(dir.path().join("a.txt").exists());
+        assert!(dir.path().join("b").exists());
+        assert!(!dir.path().join("a.gif").exists());
+
+        apply_planned(&kti, &mut run);
+        assert!(dir.path().join("a.gif").exists());
+        assert!(dir.path().join("b.gif").exists());
+    }
+
+    #[test]
+    fn two_pass_reports_whether_files_were_changed() {
+        let dir = misnamed_gifs();
+        dir.file("a.gif", b"taken");
+        let run = scan(
+            dir.path(),
+            &[
+                "--two-pass",
+                "--action",
+                "rename",
+                "--on-conflict",
+                "skip",
+                "--sort-by",
+                "size",
+                "--report-template",
+                "{name} {changed}\\n",
+            ],
+        );
+        let output: String = run
+            .held_output
+            .iter()
+            .map(|(_, held)| held.as_str())
+            .collect();
+        assert!(output.contains("a.txt false\n"));
+        assert!(output.contains("b true\n"));
+
+        let dir = misnamed_gifs();
+        let run = scan(
+            dir.path(),
+            &[
+                "--two-pass",
+                "--action",
+                "rename",
+                "--report-columns",
+                "name,changed",
+            ],
+        );
+        let mut rows: Vec<_> = run.table_rows.into_iter().map(|(_, row)| row).collect();
+        rows.sort();
+        assert_eq!(rows, [["a.txt", "true"], ["b", "true"]]);
+    }
+
+    #[test]
+    fn two_pass_keeps_hash_suffixes_for_names_taken_by_planned_renames() {
+        let dir = TempDir::new();
+        dir.file("a.txt", GIF);
+        dir.file("a", GIF);
+        scan(
+            dir.path(),
+            &[
+                "--two-pass",
+                "--action",
+                "rename",
+                "--on-conflict",
+                "rename",
+                "--suffix-mode",
+                "hash",
+            ],
+        );
+        let hash = rename::short_hash(&dir.path().join("a.gif")).unwrap();
+        assert!(dir.path().join(format!("a.{hash}.gif")).exists());
+        assert!(!dir.path().join("a (1).gif").exists());
+    }
+
+    #[test]
+    fn hash_suffixes_need_rename_conflicts() {
+        let hash = ["--suffix-mode", "hash"];
+        assert!(check_options(&kti(&hash)).is_err());
+        assert!(check_options(&kti(&[&hash[..], &["--on-conflict", "skip"]].concat())).is_err());
+        assert!(check_options(&kti(&[&hash[..], &["--on-conflict", "rename"]].concat())).is_ok());
+        assert!(check_options(&kti(&["--on-conflict", "skip"])).is_ok());
+    }
+
+    #[test]
+    fn hash_suffixes_are_the_same_on_every_run() {
+        let args = [
+            "--action",
+            "rename",
+            "--on-conflict",
+            "rename",
+            "--suffix-mode",
+            "hash",
+        ];
+        let names = |dir: &TempDir| {
+            let mut names: Vec<_> = fs::read_dir(dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            names.sort();
+            names
+        };
+        let misnamed = || {
+            let dir = TempDir::new();
+            dir.file("a.gif", b"GIF87a\x01\0\x01\0\0\0\0;");
+            dir.file("a.txt", GIF);
+            dir
+        };
+
+        let first = misnamed();
+        let hash = rename::short_hash(&first.path().join("a.txt")).unwrap();
+        scan(first.path(), &args);
+        let renamed = names(&first);
+        assert_eq!(renamed, [format!("a.{hash}.gif"), "a.gif".to_string()]);
+
+        // Running again changes nothing, and another copy of the same files
+        // ends up with the same names.
+        scan(first.path(), &args);
+        assert_eq!(names(&first), renamed);
+        let second = misnamed();
+        scan(second.path(), &args);
+        assert_eq!(names(&second), renamed);
+    }
+
+    #[test]
+    fn the_command_line_overrides_a_profile() {
+        let profile = vec!["--min-confidence=low".to_string(), "--deep".to_string()];
+        let command_line = ["kti", "--min-confidence", "high", "photos"].map(Into::into);
+        let kti = parse_with_profile(profile, command_line).unwrap();
+        assert_eq!(kti.min_confidence, Some(Confidence::High));
+        assert!(kti.deep);
+        assert_eq!(kti.path, Some(PathBuf::from("photos")));
+    }
+
+    #[test]
+    fn flags_given_twice_are_an_error_without_a_profile() {
+        let twice = ["kti", "--min-confidence", "low", "--min-confidence", "high"];
+        assert!(Kti::try_parse_from(twice).is_err());
+        assert!(parse_with_profile(Vec::new(), twice.map(Into::into)).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn names_that_are_not_utf8_are_renamed_intact() {
+        use std::os::unix::ffi::OsStrExt;
+        let dir = TempDir::new();
+        let name = OsStr::from_bytes(b"caf\xe9.txt");
+        fs::write(dir.path().join(name), GIF).unwrap();
+        let run = scan(dir.path(), &["--action", "rename"]);
+        assert_eq!(run.summary.changed, 1);
+        assert!(!dir.path().join(name).exists());
+        assert!(dir.path().join(OsStr::from_bytes(b"caf\xe9.gif")).exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_roots_are_resolved() {
+        let dir = TempDir::new();
+        dir.file("real/a.txt", GIF);
+        let real = fs::canonicalize(dir.path().join("real")).unwrap();
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+
+        let root = canonical_root(&link);
+        assert_eq!(root, real);
+        let run = scan(&root, &["--format", "json"]);
+        assert_eq!(run.reports.len(), 1);
+        assert_eq!(Path::new(&run.reports[0].path), real.join("a.txt"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn broken_symlink_roots_are_kept_as_given() {
+        let dir = TempDir::new();
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(dir.path().join("gone"), &link).unwrap();
+        assert_eq!(canonical_root(&link), std::path::absolute(&link).unwrap());
+    }
+
+    #[test]
+    fn bundles_are_reported_and_can_be_skipped() {
+        let dir = TempDir::new();
+        dir.file("Viewer.app/Contents/Info.plist", b"<plist/>");
+        dir.file("Viewer.app/Contents/Resources/icon.txt", GIF);
+        dir.file("a.txt", GIF);
+
+        let run = scan(dir.path(), &["--detect-bundles", "--format", "json"]);
+        assert_eq!(run.bundles.len(), 1);
+        assert_eq!(run.bundles[0].bundle, "application");
+        assert!(run.bundles[0].path.ends_with("Viewer.app"));
+        assert_eq!(run.diff_counter, 2);
+
+        let run = scan(
+            dir.path(),
+            &["--detect-bundles", "--skip-bundles", "--format", "json"],
+        );
+        assert_eq!(run.bundles.len(), 1);
+        assert_eq!(run.diff_counter, 1);
+        assert!(run.reports[0].path.ends_with("a.txt"));
+    }
+
+    #[test]
+    fn detections_outside_the_strict_set_are_reported_but_not_renamed() {
+        let dir = TempDir::new();
+        let allowed = TempDir::new();
+        let allowed = allowed.file("allowed", b"# what the site takes\n.PNG, jpg\n");
+        dir.file("a.txt", GIF);
+        dir.file("b.txt", include_bytes!("../samples/sample.png"));
+
+        let run = scan(
+            dir.path(),
+            &[
+                "--action",
+                "rename",
+                "--strict-extension-set",
+                allowed.to_str().unwrap(),
+            ],
+        );
+        assert_eq!(run.diff_counter, 2);
+        assert_eq!(run.summary.changed, 1);
+        assert!(dir.path().join("a.txt").exists());
+        assert!(dir.path().join("b.png").exists());
+    }
+
+    // The files of a run, relative to `dir`, sorted.
+    fn reported(dir: &TempDir, run: &Run) -> Vec<String> {
+        let mut paths: Vec<String> = run
+            .reports
+            .iter()
+            .map(|report| {
+                let path = Path::new(&report.path);
+                let relative = path.strip_prefix(dir.path()).unwrap_or(path);
+                relative.to_string_lossy().into_owned()
+            })
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hidden_links_to_visible_directories_are_skipped() {
+        use std::os::unix::fs::symlink;
+        let dir = TempDir::new();
+        dir.file("real/a.txt", GIF);
+        symlink(dir.path().join("real"), dir.path().join(".link")).unwrap();
+
+        let run = scan(dir.path(), &["-L", "--format", "json"]);
+        assert_eq!(reported(&dir, &run), ["real/a.txt"]);
+        let run = scan(dir.path(), &["-L", "-a", "--format", "json"]);
+        assert_eq!(reported(&dir, &run), [".link/a.txt", "real/a.txt"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn visible_links_to_hidden_directories_follow_check_link_targets() {
+        use std::os::unix::fs::symlink;
+        let dir = TempDir::new();
+        dir.file(".real/a.txt", GIF);
+        symlink(dir.path().join(".real"), dir.path().join("link")).unwrap();
+
+        let run = scan(dir.path(), &["-L", "--format", "json"]);
+        assert_eq!(reported(&dir, &run), ["link/a.txt"]);
+        let run = scan(
+            dir.path(),
+            &["-L", "--check-link-targets", "--format", "json"],
+        );
+        assert!(run.reports.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn excluded_directories_are_pruned_by_name_or_path() {
+        let dir = TempDir::new();
+        for name in [
+            "a.txt",
+            ".git/objects/b.txt",
+            "web/node_modules/pkg/c.txt",
+            "src/vendor/d.txt",
+            "lib/vendor/e.txt",
+            "node_modules",
+        ] {
+            dir.file(name, GIF);
+        }
+        let args = [
+            "--exclude-dir",
+            ".git",
+            "--exclude-dir",
+            "node_modules",
+            "--exclude-dir",
+            "./src/vendor",
+            "--show-hidden",
+            "--action",
+            "rename",
+            "--format",
+            "json",
+        ];
+        let run = scan(dir.path(), &args);
+        // A file with an excluded name is still checked, only directories
+        // are pruned.
+        assert_eq!(
+            reported(&dir, &run),
+            ["a.txt", "lib/vendor/e.txt", "node_modules"]
+        );
+        assert!(dir.path().join("web/node_modules/pkg/c.txt").exists());
+        assert!(dir.path().join("src/vendor/d.txt").exists());
+    }
+
+    #[test]
+    fn excluded_directories_are_pruned_when_reached_through_links() {
+        use std::os::unix::fs::symlink;
+        let dir = TempDir::new();
+        dir.file("real/a.txt", GIF);
+        symlink(dir.path().join("real"), dir.path().join("cache")).unwrap();
+
+        let run = scan(
+            dir.path(),
+            &["-L", "--exclude-dir", "cache", "--format", "json"],
+        );
+        assert_eq!(reported(&dir, &run), ["real/a.txt"]);
+    }
+
+    #[test]
+    fn repeated_extensions_are_collapsed() {
+        let collapsed = |name: &str| collapse_double_extension(Path::new(name));
+        assert_eq!(
+            collapsed("dir/photo.jpg.jpg"),
+            Some(PathBuf::from("dir/photo.jpg"))
+        );
+        assert_eq!(collapsed("a.png.png.png"), Some(PathBuf::from("a.png")));
+        assert_eq!(collapsed("a.jpg.png"), None);
+        assert_eq!(collapsed("photo.jpg"), None);
+    }
+
+    #[test]
+    fn double_extensions_are_only_fixed_when_asked_and_the_content_matches() {
+        let dir = TempDir::new();
+        dir.file("a.gif.gif", GIF);
+        dir.file("b.gif.gif.gif", GIF);
+        dir.file("c.txt.txt", b"just text\n");
+        scan(dir.path(), &["--action", "rename"]);
+        assert!(dir.path().join("a.gif.gif").exists());
+
+        scan(
+            dir.path(),
+            &["--action", "rename", "--fix-double-extensions"],
+        );
+        assert!(dir.path().join("a.gif").exists());
+        assert!(dir.path().join("b.gif").exists());
+        assert!(!dir.path().join("a.gif.gif").exists());
+        // Without a detection the content can't confirm the extension.
+        assert!(dir.path().join("c.txt.txt").exists());
+    }
+
+    #[test]
+    fn files_without_an_extension_follow_the_policy() {
+        let run_with = |policy: &str| {
+            let dir = TempDir::new();
+            dir.file("a", GIF);
+            let run = scan(
+                dir.path(),
+                &["--action", "rename", "--on-no-extension", policy],
+            );
+            (dir, run.diff_counter)
+        };
+
+        let (dir, differences) = run_with("skip");
+        assert_eq!(differences, 0);
+        assert!(dir.path().join("a").exists());
+        let (dir, differences) = run_with("report");
+        assert_eq!(differences, 1);
+        assert!(dir.path().join("a").exists());
+        let (dir, differences) = run_with("add");
+        assert_eq!(differences, 1);
+        assert!(dir.path().join("a.gif").exists());
+    }
+
+    #[test]
+    fn only_shallow_files_are_renamed_below_rename_max_depth() {
+        let dir = TempDir::new();
+        dir.file("a.txt", GIF);
+        dir.file("sub/b.txt", GIF);
+        dir.file("sub/deeper/c.txt", GIF);
+
+        let run = scan(
+            dir.path(),
+            &["--action", "rename", "--rename-max-depth", "2"],
+        );
+        assert_eq!(run.diff_counter, 3);
+        assert!(dir.path().join("a.gif").exists());
+        assert!(dir.path().join("sub/b.gif").exists());
+        assert!(dir.path().join("sub/deeper/c.txt").exists());
+
+        scan(
+            dir.path(),
+            &["--action", "rename", "--rename-max-depth", "0"],
+        );
+        assert!(dir.path().join("sub/deeper/c.txt").exists());
+    }
+
+    #[test]
+    fn files_removed_after_the_walk_are_skipped() {
+        let dir = misnamed_gifs();
+        let kti = kti(&[dir.path().to_str().unwrap()]);
+        let mut run = Run::default();
+        let path = dir.path().join("a.txt");
+        fs::remove_file(&path).unwrap();
+
+        check_file(&path, 1, dir.path(), &kti, &mut run);
+        assert_eq!(run.summary.skipped, 1);
+        assert_eq!(run.summary.errors, 0);
+        assert_eq!(run.diff_counter, 0);
+    }
+
+    #[test]
+    fn files_emptied_after_the_walk_are_read_as_empty() {
+        let dir = misnamed_gifs();
+        let kti = kti(&["--format", "json", dir.path().to_str().unwrap()]);
+        let mut run = Run::default();
+        let path = dir.path().join("a.txt");
+        fs::File::create(&path).unwrap();
+
+        check_file(&path, 1, dir.path(), &kti, &mut run);
+        assert_eq!(run.summary.errors, 0);
+        assert_eq!(run.reports.len(), 1);
+        assert_eq!(run.reports[0].detected, None);
+        assert_eq!(run.reports[0].error, None);
+    }
+
+    #[test]
+    fn backups_keep_the_original_name() {
+        let dir = TempDir::new();
+        dir.file("a.txt", GIF);
+        dir.file("b.txt", GIF);
+        let run = scan(dir.path(), &["--action", "rename", "--backup"]);
+        assert_eq!(run.summary.backups, 2);
+        assert_eq!(fs::read(dir.path().join("a.txt.bak")).unwrap(), GIF);
+        assert!(dir.path().join("a.gif").exists());
+
+        let dir = misnamed_gifs();
+        scan(dir.path(), &["--action", "rename", "--backup=.orig"]);
+        assert!(dir.path().join("a.txt.orig").exists());
+        assert!(dir.path().join("b.orig").exists());
+        let dir = misnamed_gifs();
+        let run = scan(dir.path(), &["--backup"]);
+        assert_eq!(run.summary.backups, 0);
+        assert!(!dir.path().join("a.txt.bak").exists());
+    }
+
+    #[test]
+    fn backup_names_follow_on_conflict() {
+        let dir = TempDir::new();
+        dir.file("a.txt", GIF);
+        dir.file("a.txt.bak", b"older backup");
+        let run = scan(
+            dir.path(),
+            &["--action", "rename", "--backup", "--on-conflict", "skip"],
+        );
+        assert_eq!(run.summary.backups, 0);
+        assert!(dir.path().join("a.txt").exists());
+        assert!(!dir.path().join("a.gif").exists());
+
+        let run = scan(
+            dir.path(),
+            &["--action", "rename", "--backup", "--on-conflict", "rename"],
+        );
+        assert_eq!(run.summary.backups, 1);
+        assert!(dir.path().join("a.gif").exists());
+        assert_eq!(
+            fs::read(dir.path().join("a.txt.bak")).unwrap(),
+            b"older backup"
+        );
+    }
+
+    #[test]
+    fn renames_use_the_canonical_spelling() {
+        let dir = TempDir::new();
+        dir.file(
+            "photo.txt",
+            &[0xFF, 0xD8, 0xFF, 0xE0, 0, 0x10, b'J', b'F', b'I', b'F'],
+        );
+        scan(dir.path(), &["--action", "rename", "--canonical", "long"]);
+        assert!(dir.path().join("photo.jpeg").exists());
+
+        let dir = TempDir::new();
+        dir.file(
+            "photo.jpg",
+            &[0xFF, 0xD8, 0xFF, 0xE0, 0, 0x10, b'J', b'F', b'I', b'F'],
+        );
+        let run = scan(dir.path(), &["--action", "rename", "--canonical", "long"]);
+        assert_eq!(run.summary.changed, 1);
+        assert!(dir.path().join("photo.jpeg").exists());
+    }
+
+    #[test]
+    fn concurrency_safe_renames_end_at_the_target() {
+        let dir = misnamed_gifs();
+        let run = scan(dir.path(), &["--action", "rename", "--concurrency-safe"]);
+        assert_eq!(run.summary.changed, 2);
+        let mut names: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["a.gif", "b.gif"]);
+    }
+
+    #[test]
+    fn structural_guesses_never_rename() {
+        let dir = TempDir::new();
+        dir.file("blob.dat", b"\x82\xA4name\xA3kti\xA7version\x01");
+        let args = [
+            "--action",
+            "rename",
+            "--classify-binary",
+            "--format",
+            "json",
+        ];
+        let run = scan(dir.path(), &args);
+        assert_eq!(run.summary.changed, 0);
+        assert!(dir.path().join("blob.dat").exists());
+        assert_eq!(run.reports[0].detected.as_deref(), Some("possibly-msgpack"));
+    }
+
+    #[test]
+    fn installers_keep_their_pkg_name_or_take_the_canonical_one() {
+        let xar = b"xar!\0\x1C\0\x01\0\0\0\0\0\0\x02\x00\0\0\0\0\0\0\x05\x00\0\0\0\x01";
+        let dir = TempDir::new();
+        dir.file("Installer.pkg", xar);
+        dir.file("archive.bin", xar);
+        let run = scan(dir.path(), &["--action", "rename"]);
+        assert_eq!(run.summary.changed, 1);
+        assert!(dir.path().join("Installer.pkg").exists());
+        assert!(dir.path().join("archive.xar").exists());
+
+        let dir = TempDir::new();
+        let canonical = dir.file("canonical.txt", b"xar = pkg\n");
+        dir.file("files/installer", xar);
+        let args = [
+            "--action",
+            "rename",
+            "--canonical",
+            canonical.to_str().unwrap(),
+        ];
+        scan(&dir.path().join("files"), &args);
+        assert!(dir.path().join("files/installer.pkg").exists());
+    }
+
+    #[test]
+    fn truncated_files_are_counted() {
+        let dir = TempDir::new();
+        dir.file("whole.gif", GIF);
+        dir.file("cut.gif", &GIF[..GIF.len() - 1]);
+        assert_eq!(scan(dir.path(), &["--validate"]).summary.truncated, 1);
+        assert_eq!(scan(dir.path(), &[]).summary.truncated, 0);
+    }
+
+    #[test]
+    fn safe_mode_only_renames_complete_confident_detections() {
+        let dir = TempDir::new();
+        dir.file("whole.txt", GIF);
+        dir.file("cut.txt", &GIF[..GIF.len() - 1]);
+        dir.file("subtitles.txt", include_bytes!("../samples/sample.srt"));
+        dir.file("taken.txt", GIF);
+        dir.file("taken.gif", b"GIF87a\x01\0\x01\0\0\0\0;");
+        let run = scan(dir.path(), &["--action", "rename", "--safe"]);
+        assert_eq!(run.summary.changed, 1);
+        assert_eq!(run.summary.truncated, 1);
+        for name in ["whole.gif", "cut.txt", "subtitles.txt", "taken.txt"] {
+            assert!(dir.path().join(name).exists(), "{name}");
+        }
+        assert!(
+            fs::read(dir.path().join("taken.gif"))
+                .unwrap()
+                .starts_with(b"GIF87a")
+        );
+
+        let dir = TempDir::new();
+        dir.file("cut.txt", &GIF[..GIF.len() - 1]);
+        scan(dir.path(), &["--action", "rename", "--validate"]);
+        assert!(dir.path().join("cut.gif").exists());
+    }
+
+    #[test]
+    fn safe_mode_cant_be_loosened() {
+        for option in ["--min-confidence=low", "--on-conflict=overwrite"] {
+            assert!(
+                Kti::try_parse_from(["kti", "--safe", option]).is_err(),
+                "{option}"
+            );
+        }
+    }
+
+    /// Whether files in `dir` can hold user attributes, which tmpfs before
+    /// Linux 6.6 and some other filesystems can't.
+    #[cfg(unix)]
+    fn supports_xattrs(dir: &TempDir) -> bool {
+        let probe = dir.file("probe", b"");
+        let supported = xattr::set(&probe, "user.kti.probe", b"1").is_ok();
+        fs::remove_file(probe).unwrap();
+        supported
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn detected_types_are_written_to_attributes() {
+        let dir = misnamed_gifs();
+        if !supports_xattrs(&dir) {
+            eprintln!("skipped: the temp directory doesn't support user attributes");
+            return;
+        }
+        let attribute =
+            |name: &str| xattr::get(dir.path().join(name), "user.kti.detected").unwrap();
+
+        let run = scan(dir.path(), &["--write-xattr"]);
+        assert_eq!(run.summary.xattrs_written, 2);
+        assert_eq!(attribute("a.txt"), None);
+
+        let run = scan(dir.path(), &["--action", "rename", "--write-xattr"]);
+        assert_eq!(run.summary.xattrs_written, 2);
+        assert_eq!(run.summary.changed, 0);
+        assert_eq!(attribute("a.txt").as_deref(), Some(&b"gif"[..]));
+        assert_eq!(attribute("b").as_deref(), Some(&b"gif"[..]));
+
+        let dir = misnamed_gifs();
+        scan(dir.path(), &["--action", "rename", "--write-xattr=also"]);
+        let attribute =
+            |name: &str| xattr::get(dir.path().join(name), "user.kti.detected").unwrap();
+        assert_eq!(attribute("a.gif").as_deref(), Some(&b"gif"[..]));
+        assert!(!dir.path().join("a.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn errors_fail_the_run_only_past_max_errors() {
+        use std::os::unix::fs::symlink;
+        // Links back to the directory itself make the walk report a loop,
+        // which even root can't read past.
+        let dir = misnamed_gifs();
+        for name in ["loop1", "loop2"] {
+            symlink(dir.path(), dir.path().join(name)).unwrap();
+        }
+        let run = scan(dir.path(), &["-L"]);
+        assert_eq!(run.summary.errors, 2);
+        assert_eq!(run.summary.changed, 2);
+
+        assert!(!exceeds_max_errors(&kti(&["--max-errors", "2"]), 2));
+        assert!(exceeds_max_errors(&kti(&["--max-errors", "1"]), 2));
+        assert!(exceeds_max_errors(&kti(&["--max-errors", "0"]), 1));
+        assert!(!exceeds_max_errors(&kti(&["--max-errors", "0"]), 0));
+        assert!(!exceeds_max_errors(&kti(&[]), 2));
+    }
+
+    /// Misnamed GIFs of 20, 100 and 50 bytes.
+    fn gifs_of_different_sizes() -> TempDir {
+        let dir = TempDir::new();
+        for (name, size) in [("small.txt", 20), ("large.txt", 100), ("medium.txt", 50)] {
+            let mut gif = GIF.to_vec();
+            gif.resize(size, b';');
+            dir.file(name, &gif);
+        }
+        dir
+    }
+
+    #[test]
+    fn reports_are_sorted_by_size() {
+        let dir = gifs_of_different_sizes();
+        for format in ["json", "ndjson"] {
+            let mut run = scan(dir.path(), &["--sort-by", "size", "--format", format]);
+            sort_by_size(&mut run);
+            let sizes: Vec<_> = run.reports.iter().map(|report| report.size).collect();
+            assert_eq!(sizes, [Some(100), Some(50), Some(20)], "{format}");
+        }
+
+        let args = ["--sort-by", "size", "--report-columns", "name,size"];
+        let mut run = scan(dir.path(), &args);
+        sort_by_size(&mut run);
+        let sizes: Vec<_> = run.table_rows.iter().map(|(size, _)| *size).collect();
+        assert_eq!(sizes, [100, 50, 20]);
+
+        let mut run = scan(dir.path(), &["--sort-by", "size", "--report-size"]);
+        sort_by_size(&mut run);
+        let sizes: Vec<_> = run
+            .held_output
+            .iter()
+            .map(|(size, output)| {
+                assert!(
+                    output.contains(&format!("Size: {size} bytes\n")),
+                    "{output}"
+                );
+                *size
+            })
+            .collect();
+        assert_eq!(sizes, [100, 50, 20]);
+    }
+
+    #[test]
+    fn sizes_are_only_read_when_asked_for() {
+        let dir = gifs_of_different_sizes();
+        let run = scan(dir.path(), &["--format", "json"]);
+        assert!(run.reports.iter().all(|report| report.size.is_none()));
+        let run = scan(dir.path(), &["--format", "json", "--report-size"]);
+        assert_eq!(
+            reported(&dir, &run),
+            ["large.txt", "medium.txt", "small.txt"]
+        );
+        let mut sizes: Vec<_> = run
+            .reports
+            .iter()
+            .filter_map(|report| report.size)
+            .collect();
+        sizes.sort();
+        assert_eq!(sizes, [20, 50, 100]);
+    }
+
+    #[test]
+    fn files_renamed_by_an_earlier_run_are_skipped() {
+        let dir = misnamed_gifs();
+        let logs = TempDir::new();
+        let journal = logs.path().join("journal.tsv");
+        let journal = journal.to_str().unwrap();
+        let args = [
+            "--action",
+            "rename",
+            "--journal",
+            journal,
+            "--since-journal",
+            journal,
+        ];
+        assert_eq!(scan(dir.path(), &args).summary.changed, 2);
+
+        // The renamed files are left out, a file added since isn't.
+        dir.file("c.txt", GIF);
+        let run = scan(dir.path(), &[&args[..], &["--format", "json"]].concat());
+        assert_eq!(reported(&dir, &run), ["c.txt"]);
+        assert!(dir.path().join("c.gif").exists());
+        let run = scan(
+            dir.path(),
+            &["--format", "json", "--since-journal", journal],
+        );
+        assert!(run.reports.is_empty());
+        let run = scan(dir.path(), &["--format", "json"]);
+        assert_eq!(reported(&dir, &run), ["a.gif", "b.gif", "c.gif"]);
+    }
+
+    #[test]
+    fn renamed_files_get_lowercase_names() {
+        let dir = TempDir::new();
+        dir.file("Holiday/My Photo.TXT", GIF);
+        dir.file("Holiday/Right.GIF", GIF);
+        dir.file("Holiday/Taken.txt", GIF);
+        dir.file("Holiday/taken.gif", GIF);
+        let args = [
+            "--action",
+            "rename",
+            "--lowercase-name",
+            "--on-conflict",
+            "rename",
+        ];
+        let run = scan(dir.path(), &args);
+        assert_eq!(run.summary.changed, 2);
+        for name in ["my photo.gif", "Right.GIF", "taken.gif", "taken (1).gif"] {
+            assert!(dir.path().join("Holiday").join(name).exists(), "{name}");
+        }
+        assert!(Kti::try_parse_from(["kti", "--lowercase-name", "--ext-case", "upper"]).is_err());
+    }
+
+    #[test]
+    fn paths_are_made_relative_to_any_base() {
+        let relative = |path: &str, base: &str| relative_to(Path::new(path), Path::new(base));
+        assert_eq!(
+            relative("/work/project/src/a.png", "/work/project"),
+            Some("src/a.png".into())
+        );
+        assert_eq!(relative("/work/project", "/work/project"), Some(".".into()));
+        assert_eq!(
+            relative("/work/photos/a.png", "/work/project/docs"),
+            Some("../../photos/a.png".into())
+        );
+        assert_eq!(relative("/a.png", "/work"), Some("../a.png".into()));
+        assert_eq!(relative("/work/a.png", "work"), None);
+    }
+
+    #[test]
+    fn reports_use_the_given_base() {
+        let base = TempDir::new();
+        let dir = base.path().join("scanned");
+        base.file("scanned/nested/a.txt", GIF);
+        let other = TempDir::new();
+        let cases = [
+            (
+                base.path().to_path_buf(),
+                "scanned/nested/a.txt".to_string(),
+            ),
+            (dir.join("nested"), "a.txt".to_string()),
+            (
+                other.path().to_path_buf(),
+                format!(
+                    "../{}/scanned/nested/a.txt",
+                    base.path().file_name().unwrap().to_str().unwrap()
+                ),
+            ),
+        ];
+        for (report_base, expected) in cases {
+            let args = [
+                "--format",
+                "json",
+                "--report-relative-to",
+                report_base.to_str().unwrap(),
+            ];
+            let run = scan(&dir, &args);
+            assert_eq!(run.reports[0].path, expected);
+        }
+    }
+
+    fn with_empty_file() -> TempDir {
+        let dir = TempDir::new();
+        dir.file("a.txt", GIF);
+        dir.file("download.part", b"");
+        dir
+    }
+
+    #[test]
+    fn empty_files_are_skipped_or_reported() {
+        let dir = with_empty_file();
+        let run = scan(dir.path(), &["--format", "json"]);
+        assert_eq!(run.summary.empty_files, 0);
+        assert_eq!(reported(&dir, &run), ["a.txt", "download.part"]);
+
+        let run = scan(dir.path(), &["--format", "json", "--on-empty", "skip"]);
+        assert_eq!((run.summary.empty_files, run.summary.skipped), (1, 1));
+        assert_eq!(reported(&dir, &run), ["a.txt"]);
+
+        let run = scan(dir.path(), &["--format", "json", "--on-empty", "report"]);
+        assert_eq!(run.summary.empty_files, 1);
+        assert_eq!(reported(&dir, &run), ["a.txt", "download.part"]);
+        assert!(dir.path().join("download.part").exists());
+    }
+
+    #[test]
+    fn empty_files_are_only_deleted_for_real_runs() {
+        let dir = with_empty_file();
+        let run = scan(dir.path(), &["--on-empty", "delete"]);
+        assert_eq!(run.summary.empty_files, 1);
+        assert!(dir.path().join("download.part").exists());
+
+        for args in [
+            &["--action", "rename"][..],
+            &["--action", "rename", "--two-pass"],
+        ] {
+            let dir = with_empty_file();
+            let run = scan(dir.path(), &[args, &["--on-empty", "delete"]].concat());
+            assert_eq!(run.summary.empty_files, 1);
+            assert!(!dir.path().join("download.part").exists());
+            assert!(dir.path().join("a.gif").exists());
+        }
+    }
+
+    #[test]
+    fn the_apply_preview_counts_each_outcome() {
+        let dir = misnamed_gifs();
+        dir.file("c.txt", GIF);
+        dir.file("c.gif", GIF);
+        dir.file("d.gif", GIF);
+        let args = [
+            "--action",
+            "rename",
+            "--dry-run-apply-preview",
+            "--on-conflict",
+            "skip",
+        ];
+        let run = scan(dir.path(), &args);
+        let preview = &run.preview;
+        assert_eq!(
+            (
+                preview.renamed,
+                preview.extensions_added,
+                preview.conflicts,
+                preview.left
+            ),
+            (1, 1, 1, 2)
+        );
+        assert!(dir.path().join("a.txt").exists());
+        assert!(dir.path().join("b").exists());
+    }
+
+    /// An attempt failing with `kind` the first `failures` times it is made.
+    fn failing(
+        kind: std::io::ErrorKind,
+        failures: usize,
+        attempts: &mut usize,
+    ) -> impl FnMut() -> std::io::Result<()> + '_ {
+        move || {
+            *attempts += 1;
+            if *attempts <= failures {
+                Err(kind.into())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn transient_rename_errors_are_retried() {
+        let kti = kti(&["--rename-retry", "2", "."]);
+        let mut run = Run::default();
+        let mut attempts = 0;
+        let busy = failing(std::io::ErrorKind::ResourceBusy, 2, &mut attempts);
+        assert!(retry_transient(&kti, &mut run, busy).is_ok());
+        assert_eq!(attempts, 3);
+        assert_eq!(run.summary.rename_retries, 1);
+
+        let mut attempts = 0;
+        let stale = failing(std::io::ErrorKind::StaleNetworkFileHandle, 3, &mut attempts);
+        let error = retry_transient(&kti, &mut run, stale).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::StaleNetworkFileHandle);
+        assert_eq!(attempts, 3);
+        assert_eq!(run.summary.rename_retries, 2);
+    }
+
+    #[test]
+    fn lasting_rename_errors_are_not_retried() {
+        let mut run = Run::default();
+        for kind in [
+            std::io::ErrorKind::NotFound,
+            std::io::ErrorKind::PermissionDenied,
+        ] {
+            let mut attempts = 0;
+            let kti = kti(&["--rename-retry", "5", "."]);
+            let attempt = failing(kind, 1, &mut attempts);
+            assert_eq!(
+                retry_transient(&kti, &mut run, attempt).unwrap_err().kind(),
+                kind
+            );
+            assert_eq!(attempts, 1);
+        }
+
+        let mut attempts = 0;
+        let busy = failing(std::io::ErrorKind::ResourceBusy, 1, &mut attempts);
+        assert!(retry_transient(&kti(&["."]), &mut run, busy).is_err());
+        assert_eq!(attempts, 1);
+        assert_eq!(run.summary.rename_retries, 0);
+    }
+
+    #[test]
+    fn dump_unknown_reports_undetected_files_without_renaming_them() {
+        let dir = misnamed_gifs();
+        dir.file("blob.dat", b"\x13\x37\x00\x01 unknown");
+        dir.file("other.dat", b"\x13\x37\x00\x01 unknown too");
+        let out = TempDir::new();
+        let out_arg = out.path().to_str().unwrap();
+
+        let args = [
+            "--action",
+            "rename",
+            "--dump-unknown",
+            out_arg,
+            "--dump-bytes",
+            "4",
+        ];
+        let run = scan(dir.path(), &args);
+        run.unknown.write_report(out.path()).unwrap();
+        let report = fs::read_to_string(out.path().join(unknown::REPORT_NAME)).unwrap();
+        assert!(report.starts_with("13 37 00 01  (2 files)\n"), "{report}");
+        assert!(report.contains("blob.dat") && report.contains("other.dat"));
+        assert!(!report.contains("a.txt"));
+        assert!(dir.path().join("blob.dat").exists());
+        assert!(dir.path().join("other.dat").exists());
+    }
+
+    #[test]
+    fn compare_tells_whether_two_files_are_the_same_type() {
+        let dir = TempDir::new();
+        let first = dir.file("first.txt", GIF);
+        let mut longer = GIF.to_vec();
+        longer.resize(64, b';');
+        let second = dir.file("second", &longer);
+        let song = dir.file("song.gif", M4A);
+        let blob = dir.file("blob", b"\x13\x37");
+        let other_blob = dir.file("other.blob", b"\x13\x37");
+        let kti = kti(&["."]);
+
+        assert!(compare_files(&first, &second, false, &kti).unwrap());
+        assert!(!compare_files(&first, &song, false, &kti).unwrap());
+        // Neither detected isn't the same type.
+        assert!(!compare_files(&blob, &other_blob, false, &kti).unwrap());
+    }
+
+    #[test]
+    fn compare_with_hash_also_needs_the_same_header() {
+        let dir = TempDir::new();
+        let first = dir.file("first.txt", GIF);
+        let copy = dir.file("copy", GIF);
+        let mut longer = GIF.to_vec();
+        longer.resize(64, b';');
+        let longer = dir.file("longer", &longer);
+        let kti = kti(&["."]);
+
+        assert!(compare_files(&first, &copy, true, &kti).unwrap());
+        assert!(!compare_files(&first, &longer, true, &kti).unwrap());
+        assert!(compare_files(&dir.path().join("missing"), &copy, true, &kti).is_err());
+    }
+
+    #[test]
+    fn arch_packages_keep_their_name() {
+        let dir = TempDir::new();
+        dir.file(
+            "foo-1.0-1-x86_64.pkg.tar.zst",
+            &[0x28, 0xB5, 0x2F, 0xFD, 0x04, 0x58, 0, 0],
+        );
+        let run = scan(dir.path(), &["--action", "rename"]);
+        assert_eq!(run.diff_counter, 0);
+        assert!(dir.path().join("foo-1.0-1-x86_64.pkg.tar.zst").exists());
+    }
+
+    #[test]
+    fn differences_are_colored_by_the_warn_threshold() {
+        assert_eq!(summary_count(0, Some(5)), "\x1b[32m0\x1b[39m");
+        assert_eq!(summary_count(1, Some(5)), "\x1b[33m1\x1b[39m");
+        assert_eq!(summary_count(5, Some(5)), "\x1b[33m5\x1b[39m");
+        assert_eq!(summary_count(6, Some(5)), "\x1b[91m6\x1b[39m");
+        assert_eq!(summary_count(0, Some(0)), "\x1b[32m0\x1b[39m");
+        assert_eq!(summary_count(1, Some(0)), "\x1b[91m1\x1b[39m");
+        assert_eq!(summary_count(6, None), "6");
+    }
+
+    #[test]
+    fn the_warn_threshold_needs_color() {
+        let args = ["kti", "--warn-threshold", "3", "."];
+        assert!(Kti::try_parse_from(args).is_err());
+        assert_eq!(
+            kti(&["-c", "--summary-threshold-color", "3", "."]).warn_threshold,
+            Some(3)
+        );
+    }
+
+    /// The --rename-map of a run over `dir` with `args`, as old -> new lines.
+    fn rename_map(dir: &TempDir, args: &[&str]) -> Vec<String> {
+        let out = TempDir::new();
+        let map = out.path().join("map.tsv");
+        let map_arg = map.to_str().unwrap();
+        let run = scan(dir.path(), &[args, &["--rename-map", map_arg]].concat());
+        run.rename_map.write(&map, MapFormat::Tsv).unwrap();
+        let root = dir.path().display().to_string();
+        let mut lines: Vec<String> = fs::read_to_string(&map)
+            .unwrap()
+            .lines()
+            .map(|line| line.replace(&root, "").replace('\t', " -> "))
+            .collect();
+        lines.sort();
+        lines
+    }
+
+    #[test]
+    fn rename_maps_list_the_renames_a_dry_run_would_make() {
+        let dir = misnamed_gifs();
+        dir.file("c.gif", GIF);
+        assert_eq!(rename_map(&dir, &[]), ["/a.txt -> /a.gif", "/b -> /b.gif"]);
+        assert!(dir.path().join("a.txt").exists());
+    }
+
+    #[test]
+    fn rename_maps_list_the_renames_made() {
+        let dir = misnamed_gifs();
+        dir.file("a.gif", GIF);
+        // a.txt can't take the name of a.gif, so it isn't renamed.
+        let args = ["--action", "rename", "--on-conflict", "skip"];
+        assert_eq!(rename_map(&dir, &args), ["/b -> /b.gif"]);
+        assert!(dir.path().join("b.gif").exists());
+    }
+
+    #[test]
+    fn per_extension_actions_decide_what_happens_to_each_file() {
+        let dir = TempDir::new();
+        for name in ["a.txt", "b.dat", "c.jpeg"] {
+            dir.file(name, GIF);
+        }
+        let rules = TempDir::new();
+        let rules = rules.file("rules", b"txt = rename\ndat = report\n* = skip\n");
+        let args = [
+            "--action",
+            "rename",
+            "--per-extension-action",
+            rules.to_str().unwrap(),
+        ];
+
+        let run = scan(dir.path(), &args);
+        assert_eq!(run.diff_counter, 2);
+        assert!(dir.path().join("a.gif").exists());
+        assert!(dir.path().join("b.dat").exists());
+        assert!(dir.path().join("c.jpeg").exists());
+    }
+
+    #[test]
+    fn renamed_files_get_normalized_names() {
+        let dir = TempDir::new();
+        dir.file("Cafe\u{301}.txt", GIF);
+        let run = scan(
+            dir.path(),
+            &["--action", "rename", "--normalize-unicode", "nfc"],
+        );
+        assert_eq!(run.summary.changed, 1);
+        assert!(dir.path().join("Caf\u{e9}.gif").exists());
+
+        dir.file("Cafe\u{301}.txt", GIF);
+        let run = scan(
+            dir.path(),
+            &[
+                "--action",
+                "rename",
+                "--normalize-unicode",
+                "nfc",
+                "--on-conflict",
+                "skip",
+            ],
+        );
+        assert_eq!(run.summary.changed, 0);
+        assert!(dir.path().join("Cafe\u{301}.txt").exists());
+    }
+
+    const M4A: &[u8] = b"\0\0\0\x1cftypM4A \0\0\0\0M4A mp42isom\0\0\0\x08mdat";
+
+    #[test]
+    fn ties_keep_the_current_extension_when_preferred() {
+        let dir = TempDir::new();
+        dir.file("song.mp4", M4A);
+        dir.file("clip.mov", M4A);
+        let args = ["--action", "rename", "--prefer-existing-when-tie"];
+        let run = scan(dir.path(), &args);
+        assert_eq!(run.summary.changed, 1);
+        assert!(dir.path().join("song.mp4").exists());
+        assert!(dir.path().join("clip.m4a").exists());
+
+        let dir = TempDir::new();
+        dir.file("song.mp4", M4A);
+        scan(dir.path(), &["--action", "rename"]);
+        assert!(dir.path().join("song.m4a").exists());
+    }
+
+    #[test]
+    fn tied_candidates_are_matched_regardless_of_case() {
+        let alternatives = ["mp4".to_string()];
+        let preferring = kti(&["--prefer-existing-when-tie"]);
+        assert!(is_tied_candidate(&preferring, Some("MP4"), &alternatives));
+        assert!(!is_tied_candidate(&preferring, Some("mov"), &alternatives));
+        assert!(!is_tied_candidate(&preferring, None, &alternatives));
+        assert!(!is_tied_candidate(&kti(&[]), Some("mp4"), &alternatives));
+    }
+
+    #[test]
+    fn explain_shows_every_signature_and_the_tie_break() {
+        let dir = TempDir::new();
+        let path = dir.file("song.mp4", M4A);
+        let kti = kti(&["--prefer-existing-when-tie"]);
+        let output = format_explanation(&explain(&path, None, &kti).unwrap());
+
+        assert!(output.contains("Check gif signature at 0: no match\n"));
+        assert!(output.contains("Check ftyp signature at 0: m4a (used)\n"));
+        assert!(output.contains("Check mobi signature at 60: no match\n"));
+        assert!(output.contains("Check compatible brands at 16: mp4\n"));
+        assert!(output.contains("Check fuzzy: not run, needs --fuzzy\n"));
+        assert!(output.contains("Could also be: mp4\n"));
+        assert!(
+            output.contains("Would keep: the extension is one of the types the file could be\n")
+        );
+        assert_eq!(output.matches("(used)").count(), 1);
+    }
+
+    #[test]
+    fn explain_as_json_marks_the_check_used() {
+        let dir = TempDir::new();
+        let path = dir.file("song.m4a", M4A);
+        let explanation = explain(&path, None, &kti(&[])).unwrap();
+        let json = serde_json::to_value(&explanation).unwrap();
+
+        let checks = json["checks"].as_array().unwrap();
+        let used: Vec<_> = checks
+            .iter()
+            .filter(|check| check["used"] == true)
+            .collect();
+        assert_eq!(used.len(), 1);
+        assert_eq!(used[0]["step"], "ftyp signature");
+        assert_eq!(used[0]["offset"], 0);
+        assert_eq!(used[0]["matched"], "m4a");
+        let fuzzy = checks
+            .iter()
+            .find(|check| check["step"] == "fuzzy")
+            .unwrap();
+        assert_eq!(fuzzy["needs"], "--fuzzy");
+        assert_eq!(json["detected"], "m4a");
+        assert_eq!(json["alternatives"], serde_json::json!(["mp4"]));
+        assert_eq!(json["rename"], false);
+    }
+
+    // Names can't hold tabs on Windows.
+    #[cfg(unix)]
+    #[test]
+    fn skipped_conflicts_are_logged() {
+        let dir = misnamed_gifs();
+        dir.file("a.gif", GIF);
+        dir.file("tab\there.txt", GIF);
+        dir.file("tab\there.gif", b"GIF87a");
+        let logs = TempDir::new();
+        let log_path = logs.path().join("collisions.tsv");
+        let args = [
+            "--action",
+            "rename",
+            "--on-conflict",
+            "skip",
+            "--collision-log",
+            log_path.to_str().unwrap(),
+        ];
+        let run = scan(dir.path(), &args);
+        run.collisions.write(&log_path).unwrap();
+
+        let log = fs::read_to_string(&log_path).unwrap();
+        let mut lines: Vec<&str> = log.lines().collect();
+        lines.sort();
+        let root = dir.path().display();
+        assert_eq!(
+            lines,
+            [
+                format!("{root}/a.txt\t{root}/a.gif\tsame"),
+                format!("{root}/tab\\there.txt\t{root}/tab\\there.gif\tdifferent"),
+            ]
+        );
+        assert!(dir.path().join("a.txt").exists());
+        assert!(dir.path().join("b.gif").exists());
+    }
+
+    #[test]
+    fn dry_runs_leave_the_resume_state_alone() {
+        let dir = misnamed_gifs();
+        let states = TempDir::new();
+        let state_path = states.path().join("state.json");
+        let state = serde_json::json!({
+            "root": std::path::absolute(dir.path()).unwrap(),
+            "last": std::path::absolute(dir.path().join("a.txt")).unwrap(),
+        });
+        fs::write(&state_path, state.to_string()).unwrap();
+
+        let state_arg = state_path.to_str().unwrap();
+        let run = scan(dir.path(), &["--resume", state_arg]);
+        assert_eq!(run.diff_counter, 1);
+        assert_eq!(fs::read_to_string(&state_path).unwrap(), state.to_string());
+
+        scan(dir.path(), &["--action", "rename", "--resume", state_arg]);
+        assert!(dir.path().join("a.txt").exists());
+        assert!(dir.path().join("b.gif").exists());
+        assert!(!state_path.exists());
+    }
+
+    /// Records where each flush left off in what was written.
+    #[derive(Default)]
+    struct Flushes {
+        written: Vec<u8>,
+        flushed_at: Vec<usize>,
+    }
+
+    impl Write for Flushes {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.written.extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.flushed_at.push(self.written.len());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn ndjson_lines_are_flushed_as_they_are_written() {
+        let dir = misnamed_gifs();
+        // --sort-by keeps the file reports for the end of the run.
+        let run = scan(dir.path(), &["--format", "ndjson", "--sort-by", "size"]);
+        let mut out = Flushes::default();
+        for report in &run.reports {
+            write_ndjson(&mut out, &NdjsonLine::File(report)).unwrap();
+            assert_eq!(out.flushed_at.last(), Some(&out.written.len()));
+        }
+        let summary = NdjsonLine::Summary {
+            differences: run.diff_counter,
+        };
+        write_ndjson(&mut out, &summary).unwrap();
+        assert_eq!(out.flushed_at.len(), 3);
+
+        let text = String::from_utf8(out.written).unwrap();
+        let lines: Vec<serde_json::Value> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[..2].iter().all(|line| line["type"] == "file"));
+        assert_eq!(lines[0]["detected"], "gif");
+        assert_eq!(lines[2]["type"], "summary");
+        assert_eq!(lines[2]["differences"], 2);
+    }
+
+    #[test]
+    fn ndjson_audits_stream_the_mismatches_and_end_with_a_summary() {
+        let dir = misnamed_gifs();
+        dir.file("c.gif", GIF);
+        let run = scan(dir.path(), &["--audit", "--format", "ndjson"]);
+        assert!(run.audit.mismatches.is_empty());
+
+        let mut out = Flushes::default();
+        write_ndjson(&mut out, &run.audit.ndjson_summary()).unwrap();
+        let line: serde_json::Value = serde_json::from_slice(&out.written).unwrap();
+        assert_eq!(
+            line,
+            serde_json::json!({
+                "type": "summary",
+                "matching": 1,
+                "mismatched": 1,
+                "not_detected": 0,
+                "no_extension": 1,
+            })
+        );
+    }
 }
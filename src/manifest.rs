@@ -0,0 +1,138 @@
+use crate::detect::get_correct_extension;
+use crate::{Kti, canonical_extension, different_extensions};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+struct Entry {
+    line: usize,
+    path: PathBuf,
+    expected: String,
+}
+
+/// Checks the files listed in a manifest against their expected extensions
+/// for --manifest. Each line holds `path,extension`, empty lines and lines
+/// starting with `#` are skipped, and relative paths are taken from the
+/// manifest's own directory. Returns whether every file matched.
+pub fn validate(manifest: &Path, kti: &Kti) -> Result<bool, Box<dyn Error>> {
+    let contents = fs::read_to_string(manifest)?;
+    let base = manifest.parent().unwrap_or(Path::new(""));
+    let entries = parse_manifest(&contents, base)?;
+
+    let (mut matching, mut mismatched, mut missing) = (0, 0, 0);
+    for entry in &entries {
+        let display = entry.path.to_string_lossy();
+        if !entry.path.is_file() {
+            eprintln!("{}: listed on line {} but not found", display, entry.line);
+            missing += 1;
+            continue;
+        }
+        let detected = match get_correct_extension(&entry.path, kti) {
+            Ok(Some(detection)) => canonical_extension(kti, detection.extension),
+            Ok(None) => {
+                println!("{}: expected {}, not detected", display, entry.expected);
+                mismatched += 1;
+                continue;
+            }
+            Err(e) => {
+                eprintln!("{}: could not be read: {}", display, e);
+                missing += 1;
+                continue;
+            }
+        };
+        if different_extensions(&entry.expected, &detected) {
+            println!(
+                "{}: expected {}, detected {}",
+                display, entry.expected, detected
+            );
+            mismatched += 1;
+        } else {
+            matching += 1;
+        }
+    }
+
+    println!(
+        "Manifest entries: {}, matching: {}, mismatched: {}, missing: {}",
+        entries.len(),
+        matching,
+        mismatched,
+        missing
+    );
+    Ok(mismatched == 0 && missing == 0)
+}
+
+fn parse_manifest(contents: &str, base: &Path) -> Result<Vec<Entry>, String> {
+    let mut entries = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // Split at the last comma so paths may contain commas themselves.
+        let Some((path, expected)) = line.rsplit_once(',') else {
+            return Err(format!(
+                "line {}: expected 'path,extension', got '{}'",
+                number + 1,
+                line
+            ));
+        };
+        let expected = expected.trim().trim_start_matches('.');
+        if path.trim().is_empty() || expected.is_empty() {
+            return Err(format!("line {}: missing path or extension", number + 1));
+        }
+        entries.push(Entry {
+            line: number + 1,
+            path: base.join(path.trim()),
+            expected: expected.to_string(),
+        });
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{TempDir, kti};
+
+    const GIF: &[u8] = b"GIF89a\x01\0\x01\0\0\0\0;";
+
+    #[test]
+    fn matching_entries_pass() {
+        let dir = TempDir::new();
+        dir.file("images/a.gif", GIF);
+        dir.file("images/b,c.txt", GIF);
+        let manifest = dir.file(
+            "manifest.csv",
+            b"# expected types\n\nimages/a.gif,gif\nimages/b,c.txt, .GIF\n",
+        );
+        assert!(validate(&manifest, &kti(&[])).unwrap());
+    }
+
+    #[test]
+    fn mismatched_and_missing_entries_fail() {
+        let dir = TempDir::new();
+        dir.file("a.gif", GIF);
+        dir.file("notes.txt", b"just text");
+        let mismatched = dir.file("mismatched.csv", b"a.gif,png\n");
+        assert!(!validate(&mismatched, &kti(&[])).unwrap());
+        let undetected = dir.file("undetected.csv", b"notes.txt,pdf\n");
+        assert!(!validate(&undetected, &kti(&[])).unwrap());
+        let missing = dir.file("missing.csv", b"a.gif,gif\ngone.gif,gif\n");
+        assert!(!validate(&missing, &kti(&[])).unwrap());
+    }
+
+    #[test]
+    fn manifest_lines_are_checked() {
+        let base = Path::new("root");
+        let entries = parse_manifest("dir/a,b.png,png\n", base).unwrap();
+        assert_eq!(entries[0].path, Path::new("root/dir/a,b.png"));
+        assert_eq!(entries[0].expected, "png");
+        assert_eq!(entries[0].line, 1);
+
+        let error = parse_manifest("# header\na.png\n", base).err().unwrap();
+        assert_eq!(error, "line 2: expected 'path,extension', got 'a.png'");
+        let error = parse_manifest("a.png, \n", base).err().unwrap();
+        assert_eq!(error, "line 1: missing path or extension");
+        assert!(validate(Path::new("/nonexistent/manifest.csv"), &kti(&[])).is_err());
+    }
+}
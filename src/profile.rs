@@ -0,0 +1,99 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where kti looks for profiles when --config isn't given:
+/// `$XDG_CONFIG_HOME/kti/config`, or `~/.config/kti/config`.
+pub fn default_config_path() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("kti").join("config"))
+}
+
+/// Reads the flags of profile `name` from `config` for --profile, ready to be
+/// put in front of the command line arguments.
+///
+/// Each profile starts with a `[name]` line followed by one flag per line,
+/// written without the leading dashes, either on its own (`deep`) or with a
+/// value (`ext-case = preserve`). Empty lines and lines starting with `#` are
+/// ignored.
+pub fn profile_args(config: &Path, name: &str) -> Result<Vec<String>, String> {
+    let contents = fs::read_to_string(config)
+        .map_err(|e| format!("could not read {}: {e}", config.display()))?;
+    let profiles = parse_profiles(&contents)?;
+    profiles
+        .into_iter()
+        .find(|(profile, _)| profile == name)
+        .map(|(_, args)| args)
+        .ok_or_else(|| format!("there is no profile '{name}' in {}", config.display()))
+}
+
+fn parse_profiles(contents: &str) -> Result<Vec<(String, Vec<String>)>, String> {
+    let mut profiles: Vec<(String, Vec<String>)> = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            profiles.push((name.trim().to_string(), Vec::new()));
+            continue;
+        }
+        let Some((_, args)) = profiles.last_mut() else {
+            return Err(format!(
+                "line {}: '{line}' is not part of a profile, start one with '[name]'",
+                number + 1
+            ));
+        };
+        let (flag, value) = match line.split_once('=') {
+            Some((flag, value)) => (flag.trim(), Some(value.trim())),
+            None => (line, None),
+        };
+        let flag = flag.trim_start_matches('-');
+        if flag.is_empty() {
+            return Err(format!("line {}: missing flag in '{line}'", number + 1));
+        }
+        if flag == "profile" || flag == "config" {
+            return Err(format!(
+                "line {}: a profile can't select another profile or config file",
+                number + 1
+            ));
+        }
+        args.push(match value {
+            Some(value) => format!("--{flag}={value}"),
+            None => format!("--{flag}"),
+        });
+    }
+    Ok(profiles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    const CONFIG: &str = "# the usual\n[photos]\nvalidate\n--ext-case = lower\n\n[audio]\ndeep\n";
+
+    #[test]
+    fn profiles_expand_to_flags() {
+        let dir = TempDir::new();
+        let config = dir.file("config", CONFIG.as_bytes());
+        assert_eq!(
+            profile_args(&config, "photos").unwrap(),
+            ["--validate", "--ext-case=lower"]
+        );
+        assert_eq!(profile_args(&config, "audio").unwrap(), ["--deep"]);
+        let e = profile_args(&config, "video").unwrap_err();
+        assert!(e.starts_with("there is no profile 'video'"));
+    }
+
+    #[test]
+    fn flags_outside_a_profile_are_rejected() {
+        let e = parse_profiles("deep\n[photos]\n").unwrap_err();
+        assert!(e.starts_with("line 1:"));
+        assert!(parse_profiles("[a]\nprofile = b\n").is_err());
+        assert!(parse_profiles("[a]\n--\n").is_err());
+    }
+}
@@ -0,0 +1,489 @@
+use clap::ValueEnum;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use unicode_normalization::UnicodeNormalization;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ConflictPolicy {
+    /// Leaves the file alone
+    Skip,
+    /// Replaces the existing file
+    Overwrite,
+    /// Picks a free name like "photo (1).jpg"
+    Rename,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Action {
+    /// Only reports the files, like --dry-run
+    Report,
+    /// Renames the files where they are
+    Rename,
+    /// Copies the files to --dest under their new name, keeping the originals
+    Copy,
+    /// Moves the files to --dest under their new name
+    Move,
+    /// Only adds the detected extension to files that have none
+    AddExt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExtCase {
+    /// Follows the case of the current extension, lowercase if there is none
+    Preserve,
+    /// Writes extensions as detected, which is lowercase
+    Lower,
+    /// Always writes uppercase extensions
+    Upper,
+}
+
+/// Puts the detected extension into the case asked for with --ext-case.
+/// `current` is the extension the file has now. Detected extensions are
+/// already lowercase, so lower keeps them, and a --canonical spelling, as is.
+pub fn apply_ext_case(extension: String, current: Option<&str>, case: ExtCase) -> String {
+    let upper = match case {
+        ExtCase::Lower => false,
+        ExtCase::Upper => true,
+        // Mixed case like "Jpg" is rare enough to be treated as lowercase.
+        ExtCase::Preserve => current.is_some_and(|current| {
+            current.chars().any(|c| c.is_alphabetic()) && !current.chars().any(|c| c.is_lowercase())
+        }),
+    };
+    if upper {
+        extension.to_uppercase()
+    } else {
+        extension
+    }
+}
+
+/// Lowercases the file name of `path` for --lowercase-name. Names that aren't
+/// valid UTF-8 are left as they are, as there is no telling what their case is.
+pub fn lowercase_file_name(path: &Path) -> PathBuf {
+    match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => path.with_file_name(name.to_lowercase()),
+        None => path.to_path_buf(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum UnicodeForm {
+    /// Composed characters, as most systems write them
+    Nfc,
+    /// Decomposed characters, as older macOS file systems store them
+    Nfd,
+}
+
+/// Brings the stem of `path` into the Unicode normalization `form` for
+/// --normalize-unicode, keeping the extension as it is. Like with
+/// --lowercase-name, names that aren't valid UTF-8 are left alone.
+pub fn normalize_file_stem(path: &Path, form: UnicodeForm) -> PathBuf {
+    let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+        return path.to_path_buf();
+    };
+    let mut name: OsString = match form {
+        UnicodeForm::Nfc => stem.nfc().collect::<String>(),
+        UnicodeForm::Nfd => stem.nfd().collect::<String>(),
+    }
+    .into();
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+// Characters Windows doesn't allow in file names, on top of control
+// characters.
+const UNSAFE_CHARS: [char; 9] = ['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+fn is_unsafe(c: char) -> bool {
+    c.is_control() || UNSAFE_CHARS.contains(&c)
+}
+
+/// Replaces the characters of the stem of `path` that aren't safe on every
+/// file system with `replacement` for --sanitize, keeping the extension as it
+/// is. Names that aren't valid UTF-8 are left alone, and so are stems that
+/// would be dropped entirely, as the file would then be hidden.
+pub fn sanitize_file_stem(path: &Path, replacement: &str) -> PathBuf {
+    let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+        return path.to_path_buf();
+    };
+    let mut name = OsString::new();
+    for c in stem.chars() {
+        if is_unsafe(c) {
+            name.push(replacement);
+        } else {
+            name.push(c.encode_utf8(&mut [0; 4]));
+        }
+    }
+    if name.is_empty() {
+        return path.to_path_buf();
+    }
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+/// Accepts a single safe character for --sanitize, or nothing to drop unsafe
+/// characters.
+pub fn parse_replacement(value: &str) -> Result<String, String> {
+    let mut chars = value.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if is_unsafe(c) => Err(format!("'{c}' is not safe in file names itself")),
+        (None, _) | (Some(_), None) => Ok(value.to_string()),
+        _ => Err(format!("expected a single character, got '{value}'")),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum XattrMode {
+    /// Records the type and keeps the file name
+    Only,
+    /// Records the type and renames the file as well
+    Also,
+}
+
+const XATTR_NAME: &str = "user.kti.detected";
+
+/// Stores the detected type in the `user.kti.detected` extended attribute,
+/// for --write-xattr.
+#[cfg(unix)]
+pub fn write_detected_xattr(path: &Path, extension: &str) -> io::Result<()> {
+    xattr::set(path, XATTR_NAME, extension.as_bytes())
+}
+
+#[cfg(not(unix))]
+pub fn write_detected_xattr(_path: &Path, _extension: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{XATTR_NAME} can't be written on this platform"),
+    ))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SuffixMode {
+    /// Adds a number, as in `photo (1).jpg`
+    Number,
+    /// Adds a short hash of the file's contents, as in `photo.ab12cd.jpg`
+    Hash,
+}
+
+/// Like `resolve_conflict` with `ConflictPolicy::Rename`, but for
+/// --suffix-mode hash: a taken name gets a short hash of the contents of
+/// `source`, so the same file ends up with the same name on every run. Only
+/// if that is taken too a number is added.
+pub fn resolve_conflict_by_hash(source: &Path, target: &Path) -> io::Result<Option<PathBuf>> {
+    if !path_taken(target) {
+        return Ok(Some(target.to_path_buf()));
+    }
+    let mut name = OsString::from(target.file_stem().unwrap_or_default());
+    name.push(format!(".{}", short_hash(source)?));
+    if let Some(extension) = target.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    Ok(resolve_conflict(
+        &target.with_file_name(name),
+        ConflictPolicy::Rename,
+    ))
+}
+
+// The low 24 bits of an FNV-1a hash of the whole file, as 6 hex digits.
+pub fn short_hash(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut buffer = [0; 64 * 1024];
+    let mut hash: u64 = 0xcbf29ce484222325;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        for byte in &buffer[..read] {
+            hash = (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3);
+        }
+    }
+    Ok(format!("{:06x}", hash & 0xFF_FFFF))
+}
+
+/// Works out where a file should go when `target` may already be taken.
+/// Returns `None` when the policy says to leave the file alone.
+pub fn resolve_conflict(target: &Path, policy: ConflictPolicy) -> Option<PathBuf> {
+    if !path_taken(target) {
+        return Some(target.to_path_buf());
+    }
+    match policy {
+        ConflictPolicy::Skip => None,
+        ConflictPolicy::Overwrite => Some(target.to_path_buf()),
+        ConflictPolicy::Rename => (1..)
+            .map(|n| numbered_path(target, n))
+            .find(|candidate| !path_taken(candidate)),
+    }
+}
+
+fn path_taken(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+fn numbered_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = OsString::from(path.file_stem().unwrap_or_default());
+    name.push(format!(" ({n})"));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+/// Keeps the original reachable under its old name plus `suffix` before it gets
+/// renamed. A hard link is used where possible so no data has to be copied.
+/// Returns `None` if the backup name is taken and the policy says to skip.
+pub fn create_backup(
+    path: &Path,
+    suffix: &str,
+    policy: ConflictPolicy,
+) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(suffix);
+    let Some(backup) = resolve_conflict(Path::new(&backup), policy) else {
+        return Ok(None);
+    };
+
+    if path_taken(&backup) {
+        fs::remove_file(&backup)?;
+    }
+    if fs::hard_link(path, &backup).is_err() {
+        fs::copy(path, &backup)?;
+    }
+    Ok(Some(backup))
+}
+
+/// Where --action copy or move puts `path`: the same path below `dest` as
+/// below the scanned `root`, or just the file name when `root` is the file.
+pub fn destination_path(path: &Path, root: &Path, dest: &Path) -> PathBuf {
+    match path.strip_prefix(root) {
+        Ok(relative) if !relative.as_os_str().is_empty() => dest.join(relative),
+        _ => dest.join(path.file_name().unwrap_or_default()),
+    }
+}
+
+/// Moves `path` to `target`, copying it and removing the original when they
+/// are on different filesystems.
+pub fn move_file(path: &Path, target: &Path) -> io::Result<()> {
+    match fs::rename(path, target) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            fs::copy(path, target)?;
+            fs::remove_file(path)
+        }
+        result => result,
+    }
+}
+
+/// Renames `path` in two steps through a temporary name in the same directory,
+/// used with --concurrency-safe. If kti is interrupted in between, the file is
+/// left as `.<old name>.kti-<pid>.tmp` instead of half replacing the target,
+/// and it can simply be renamed back.
+pub fn rename_via_temp(path: &Path, target: &Path, policy: ConflictPolicy) -> io::Result<()> {
+    let temp = temp_path(path);
+    fs::rename(path, &temp)?;
+
+    let result = match policy {
+        ConflictPolicy::Overwrite => fs::rename(&temp, target),
+        ConflictPolicy::Skip | ConflictPolicy::Rename => move_no_clobber(&temp, target),
+    };
+    if let Err(e) = result {
+        // Put the file back so a failed rename leaves nothing behind. If even
+        // that fails, the temporary file still holds the original.
+        let _ = fs::rename(&temp, path);
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".kti-{}.tmp", std::process::id()));
+    path.with_file_name(name)
+}
+
+// Another process may have taken the target since the conflict check. A hard
+// link fails instead of replacing an existing file, so it is used to claim the
+// name. Filesystems without hard links fall back to a plain rename.
+fn move_no_clobber(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::hard_link(from, to) {
+        Ok(()) => fs::remove_file(from),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Err(e),
+        Err(_) => fs::rename(from, to),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn lower_keeps_detected_case() {
+        assert_eq!(
+            apply_ext_case("jpg".into(), Some("PNG"), ExtCase::Lower),
+            "jpg"
+        );
+        assert_eq!(apply_ext_case("jpg".into(), None, ExtCase::Lower), "jpg");
+    }
+
+    #[test]
+    fn upper_always_uppercases() {
+        assert_eq!(
+            apply_ext_case("jpg".into(), Some("png"), ExtCase::Upper),
+            "JPG"
+        );
+        assert_eq!(apply_ext_case("jpg".into(), None, ExtCase::Upper), "JPG");
+    }
+
+    #[test]
+    fn preserve_follows_current_extension() {
+        assert_eq!(
+            apply_ext_case("jpg".into(), Some("PNG"), ExtCase::Preserve),
+            "JPG"
+        );
+        assert_eq!(
+            apply_ext_case("jpg".into(), Some("png"), ExtCase::Preserve),
+            "jpg"
+        );
+        assert_eq!(
+            apply_ext_case("jpg".into(), Some("Png"), ExtCase::Preserve),
+            "jpg"
+        );
+        assert_eq!(
+            apply_ext_case("mp4".into(), Some("001"), ExtCase::Preserve),
+            "mp4"
+        );
+        assert_eq!(apply_ext_case("jpg".into(), None, ExtCase::Preserve), "jpg");
+    }
+
+    #[test]
+    fn unsafe_characters_are_replaced_in_the_stem_only() {
+        assert_eq!(
+            sanitize_file_stem(Path::new("dir/a:b?c\tEND.txt"), "_"),
+            Path::new("dir/a_b_c_END.txt")
+        );
+        assert_eq!(
+            sanitize_file_stem(Path::new("what*<now>|.jpg"), "-"),
+            Path::new("what--now--.jpg")
+        );
+        assert_eq!(
+            sanitize_file_stem(Path::new("plain name"), "_"),
+            Path::new("plain name")
+        );
+    }
+
+    #[test]
+    fn unsafe_characters_can_be_dropped() {
+        assert_eq!(
+            sanitize_file_stem(Path::new("a:b\\c?.png"), ""),
+            Path::new("abc.png")
+        );
+        // Dropping every character would leave a hidden ".png".
+        assert_eq!(
+            sanitize_file_stem(Path::new("???.png"), ""),
+            Path::new("???.png")
+        );
+    }
+
+    #[test]
+    fn replacements_have_to_be_safe() {
+        assert_eq!(parse_replacement("_"), Ok("_".to_string()));
+        assert_eq!(parse_replacement(""), Ok(String::new()));
+        assert!(parse_replacement(":").is_err());
+        assert!(parse_replacement("ab").is_err());
+    }
+
+    #[test]
+    fn lowercasing_leaves_the_directories_alone() {
+        assert_eq!(
+            lowercase_file_name(Path::new("Photos/My Trip.JPG")),
+            Path::new("Photos/my trip.jpg")
+        );
+        assert_eq!(
+            lowercase_file_name(Path::new("ÄRGER.Txt")),
+            Path::new("ärger.txt")
+        );
+    }
+
+    #[test]
+    fn stems_are_normalized_and_extensions_kept() {
+        let decomposed = Path::new("Photos/Cafe\u{301}.jpg");
+        let composed = Path::new("Photos/Caf\u{e9}.jpg");
+        assert_eq!(normalize_file_stem(decomposed, UnicodeForm::Nfc), composed);
+        assert_eq!(normalize_file_stem(composed, UnicodeForm::Nfd), decomposed);
+        assert_eq!(normalize_file_stem(composed, UnicodeForm::Nfc), composed);
+        assert_eq!(
+            normalize_file_stem(Path::new("Cafe\u{301}.e\u{301}"), UnicodeForm::Nfc),
+            Path::new("Caf\u{e9}.e\u{301}")
+        );
+        assert_eq!(
+            normalize_file_stem(Path::new("Cafe\u{301}"), UnicodeForm::Nfc),
+            Path::new("Caf\u{e9}")
+        );
+    }
+
+    fn leftovers(dir: &TempDir) -> Vec<String> {
+        fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".tmp"))
+            .collect()
+    }
+
+    #[test]
+    fn renames_via_temp_leave_nothing_behind() {
+        let dir = TempDir::new();
+        let path = dir.file("photo.txt", b"photo");
+        let target = dir.path().join("photo.jpg");
+        rename_via_temp(&path, &target, ConflictPolicy::Skip).unwrap();
+        assert!(!path.exists());
+        assert_eq!(fs::read(&target).unwrap(), b"photo");
+        assert!(leftovers(&dir).is_empty());
+    }
+
+    #[test]
+    fn temp_names_are_hidden_next_to_the_file() {
+        let temp = temp_path(Path::new("dir/photo.txt"));
+        let expected = format!("dir/.photo.txt.kti-{}.tmp", std::process::id());
+        assert_eq!(temp, Path::new(&expected));
+    }
+
+    #[test]
+    fn a_target_taken_in_between_is_not_replaced() {
+        // Another process claims the target after the conflict check, which
+        // is the window --concurrency-safe guards.
+        let dir = TempDir::new();
+        let path = dir.file("photo.txt", b"photo");
+        let target = dir.file("photo.jpg", b"someone else's");
+        for policy in [ConflictPolicy::Skip, ConflictPolicy::Rename] {
+            let error = rename_via_temp(&path, &target, policy).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+            assert_eq!(fs::read(&path).unwrap(), b"photo");
+            assert_eq!(fs::read(&target).unwrap(), b"someone else's");
+            assert!(leftovers(&dir).is_empty());
+        }
+    }
+
+    #[test]
+    fn an_interrupted_rename_puts_the_file_back() {
+        // A directory in the way makes the second step fail, as if kti had
+        // been stopped after moving the file to its temporary name.
+        let dir = TempDir::new();
+        let path = dir.file("photo.txt", b"photo");
+        dir.file("photo.jpg/inside", b"");
+        let target = dir.path().join("photo.jpg");
+        assert!(rename_via_temp(&path, &target, ConflictPolicy::Overwrite).is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"photo");
+        assert!(leftovers(&dir).is_empty());
+    }
+}
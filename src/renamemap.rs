@@ -0,0 +1,92 @@
+use clap::ValueEnum;
+use serde::Serialize;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MapFormat {
+    /// One 'old path<TAB>new path' line per rename
+    Tsv,
+    /// An array of objects with an old and a new path
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+struct Mapping {
+    old: String,
+    new: String,
+}
+
+/// The renames of a run for --rename-map, the ones a dry run would make or
+/// the ones that were made. Paths are kept as kti was given them, so the map
+/// can be applied to a copy of the tree elsewhere.
+#[derive(Debug, Default)]
+pub struct RenameMap {
+    mappings: Vec<Mapping>,
+}
+
+impl RenameMap {
+    pub fn add(&mut self, old: &Path, new: &Path) {
+        self.mappings.push(Mapping {
+            old: old.display().to_string(),
+            new: new.display().to_string(),
+        });
+    }
+
+    pub fn write(&self, file: &Path, format: MapFormat) -> io::Result<()> {
+        let mut out = fs::File::create(file)?;
+        match format {
+            MapFormat::Tsv => {
+                for mapping in &self.mappings {
+                    writeln!(out, "{}\t{}", mapping.old, mapping.new)?;
+                }
+            }
+            MapFormat::Json => {
+                serde_json::to_writer_pretty(&mut out, &self.mappings)?;
+                writeln!(out)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn map() -> RenameMap {
+        let mut map = RenameMap::default();
+        map.add(Path::new("photos/a.txt"), Path::new("photos/a.jpg"));
+        map.add(Path::new("b"), Path::new("b.gif"));
+        map
+    }
+
+    #[test]
+    fn tsv_maps_have_a_line_per_rename() {
+        let dir = TempDir::new();
+        let file = dir.path().join("map.tsv");
+        map().write(&file, MapFormat::Tsv).unwrap();
+        assert_eq!(
+            fs::read_to_string(file).unwrap(),
+            "photos/a.txt\tphotos/a.jpg\nb\tb.gif\n"
+        );
+    }
+
+    #[test]
+    fn json_maps_are_an_array_of_old_and_new_paths() {
+        let dir = TempDir::new();
+        let file = dir.path().join("map.json");
+        map().write(&file, MapFormat::Json).unwrap();
+        let mappings: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(file).unwrap()).unwrap();
+        assert_eq!(
+            mappings,
+            serde_json::json!([
+                {"old": "photos/a.txt", "new": "photos/a.jpg"},
+                {"old": "b", "new": "b.gif"},
+            ])
+        );
+    }
+}
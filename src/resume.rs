@@ -0,0 +1,159 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// How many files are checked between two checkpoints.
+const CHECKPOINT_EVERY: usize = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct State {
+    root: PathBuf,
+    last: PathBuf,
+}
+
+/// Progress through a scan for --resume, kept in a state file that holds the
+/// scanned directory and the last file checked. The files are walked in
+/// name order with --resume, so everything up to that file was done already.
+#[derive(Debug, Clone)]
+pub struct Resume {
+    file: PathBuf,
+    state: Option<State>,
+    unsaved: usize,
+}
+
+impl Resume {
+    /// Forgets the saved progress if it was for another directory than
+    /// `root`, so a state file can't make kti skip files it never checked.
+    pub fn start(&mut self, root: &Path) -> io::Result<()> {
+        let root = std::path::absolute(root)?;
+        if let Some(state) = &self.state
+            && state.root != root
+        {
+            eprintln!(
+                "{:?} was saved for {:?}, starting over.",
+                self.file, state.root
+            );
+            self.state = None;
+        }
+        if self.state.is_none() {
+            self.state = Some(State {
+                root,
+                last: PathBuf::new(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether an earlier run already got past `path`. A directory is done
+    /// when the last file checked comes after everything inside it.
+    pub fn is_done(&self, path: &Path, is_dir: bool) -> bool {
+        let Some(state) = &self.state else {
+            return false;
+        };
+        let Ok(path) = std::path::absolute(path) else {
+            return false;
+        };
+        if is_dir {
+            path < state.last && !state.last.starts_with(&path)
+        } else {
+            path <= state.last
+        }
+    }
+
+    /// Notes that `path` was checked, saving the progress every so often.
+    pub fn checked(&mut self, path: &Path) -> io::Result<()> {
+        if let Some(state) = &mut self.state {
+            state.last = std::path::absolute(path)?;
+        }
+        self.unsaved += 1;
+        if self.unsaved >= CHECKPOINT_EVERY {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the progress to a temporary file next to the state file and
+    /// renames it over it, so an interruption never leaves half a state file.
+    pub fn save(&mut self) -> io::Result<()> {
+        let Some(state) = &self.state else {
+            return Ok(());
+        };
+        let mut temp = self.file.clone().into_os_string();
+        temp.push(".tmp");
+        fs::write(&temp, serde_json::to_string_pretty(state)?)?;
+        fs::rename(&temp, &self.file)?;
+        self.unsaved = 0;
+        Ok(())
+    }
+
+    /// Removes the state file once the whole tree was checked, so the next
+    /// run starts from the beginning.
+    pub fn finish(&self) -> io::Result<()> {
+        match fs::remove_file(&self.file) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Reads the state file for --resume. One that doesn't exist yet means the
+/// scan starts from the beginning.
+pub fn parse_resume(value: &str) -> Result<Resume, String> {
+    let state = match fs::read_to_string(value) {
+        Ok(contents) => Some(
+            serde_json::from_str(&contents)
+                .map_err(|e| format!("could not read '{value}': {e}"))?,
+        ),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(format!("could not read '{value}': {e}")),
+    };
+    Ok(Resume {
+        file: PathBuf::from(value),
+        state,
+        unsaved: 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn files_up_to_the_last_checked_are_done() {
+        let dir = TempDir::new();
+        let root = dir.path();
+        let mut resume = parse_resume(root.join("state").to_str().unwrap()).unwrap();
+        resume.start(root).unwrap();
+        assert!(!resume.is_done(&root.join("a"), false));
+
+        resume.checked(&root.join("b/2.txt")).unwrap();
+        assert!(resume.is_done(&root.join("a"), true));
+        assert!(resume.is_done(&root.join("b/1.txt"), false));
+        assert!(resume.is_done(&root.join("b/2.txt"), false));
+        // Not all of b was checked yet, and c comes after the last file.
+        assert!(!resume.is_done(&root.join("b"), true));
+        assert!(!resume.is_done(&root.join("b/3.txt"), false));
+        assert!(!resume.is_done(&root.join("c"), true));
+    }
+
+    #[test]
+    fn progress_for_another_root_is_forgotten() {
+        let dir = TempDir::new();
+        let root = dir.path();
+        let state = root.join("state");
+        let mut resume = parse_resume(state.to_str().unwrap()).unwrap();
+        resume.start(&root.join("photos")).unwrap();
+        resume.checked(&root.join("photos/b.jpg")).unwrap();
+        resume.save().unwrap();
+
+        let mut resume = parse_resume(state.to_str().unwrap()).unwrap();
+        resume.start(&root.join("photos")).unwrap();
+        assert!(resume.is_done(&root.join("photos/a.jpg"), false));
+
+        let mut resume = parse_resume(state.to_str().unwrap()).unwrap();
+        resume.start(&root.join("music")).unwrap();
+        assert!(!resume.is_done(&root.join("photos/a.jpg"), false));
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+
+/// What --per-extension-action does with a mismatched file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleAction {
+    /// Handles the file like any other, with --action
+    Rename,
+    /// Reports the file as a difference without touching it
+    Report,
+    /// Leaves the file alone and does not count it as a difference
+    Skip,
+}
+
+/// The action for each current extension, read from the --per-extension-action
+/// file. A rule for the extension itself wins over the `*` rule.
+#[derive(Debug, Clone)]
+pub struct ExtensionRules {
+    rules: HashMap<String, RuleAction>,
+    fallback: Option<RuleAction>,
+}
+
+impl ExtensionRules {
+    /// The action for a file whose extension is `current`, if any rule has one.
+    pub fn action_for(&self, current: &str) -> Option<RuleAction> {
+        self.rules
+            .get(&current.to_lowercase())
+            .copied()
+            .or(self.fallback)
+    }
+}
+
+/// Reads a file with one `extensions = action` rule per line, such as
+/// `txt, md = rename` or `* = report`. Empty lines and lines starting with `#`
+/// are ignored.
+pub fn parse_extension_rules(value: &str) -> Result<ExtensionRules, String> {
+    let contents =
+        fs::read_to_string(value).map_err(|e| format!("could not read '{value}': {e}"))?;
+    let mut rules = HashMap::new();
+    let mut fallback = None;
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((extensions, action)) = line.split_once('=') else {
+            return Err(format!(
+                "line {}: expected 'extensions = action', got '{line}'",
+                number + 1
+            ));
+        };
+        let action = match action.trim() {
+            "rename" => RuleAction::Rename,
+            "report" => RuleAction::Report,
+            "skip" => RuleAction::Skip,
+            other => {
+                return Err(format!(
+                    "line {}: unknown action '{other}', expected rename, report or skip",
+                    number + 1
+                ));
+            }
+        };
+        for extension in extensions.split(',') {
+            let extension = extension.trim().trim_start_matches('.').to_lowercase();
+            let previous = match extension.as_str() {
+                "" => {
+                    return Err(format!(
+                        "line {}: missing extension in '{line}'",
+                        number + 1
+                    ));
+                }
+                "*" => fallback.replace(action),
+                _ => rules.insert(extension.clone(), action),
+            };
+            if previous.is_some() {
+                return Err(format!(
+                    "line {}: '{extension}' already has a rule",
+                    number + 1
+                ));
+            }
+        }
+    }
+    Ok(ExtensionRules { rules, fallback })
+}
+
+/// The extensions kti may rename files to, read from the
+/// --strict-extension-set file.
+#[derive(Debug, Clone)]
+pub struct ExtensionSet {
+    extensions: HashSet<String>,
+}
+
+impl ExtensionSet {
+    pub fn contains(&self, extension: &str) -> bool {
+        self.extensions.contains(&extension.to_lowercase())
+    }
+}
+
+/// Reads a file of extensions separated by commas, spaces or new lines, such
+/// as `jpg, png, pdf`. Lines starting with `#` are ignored.
+pub fn parse_extension_set(value: &str) -> Result<ExtensionSet, String> {
+    let contents =
+        fs::read_to_string(value).map_err(|e| format!("could not read '{value}': {e}"))?;
+    let extensions: HashSet<String> = contents
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .flat_map(|line| line.split(|c: char| c == ',' || c.is_whitespace()))
+        .map(|extension| extension.trim_start_matches('.').to_lowercase())
+        .filter(|extension| !extension.is_empty())
+        .collect();
+    if extensions.is_empty() {
+        return Err(format!("'{value}' doesn't list any extensions"));
+    }
+    Ok(ExtensionSet { extensions })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn parsed(contents: &str) -> Result<ExtensionRules, String> {
+        let dir = TempDir::new();
+        let path = dir.file("rules", contents.as_bytes());
+        parse_extension_rules(path.to_str().unwrap())
+    }
+
+    #[test]
+    fn extension_rules_win_over_the_fallback() {
+        let rules = parsed("# mine\ntxt, .MD = rename\ndat = report\n\n* = skip\n").unwrap();
+        assert_eq!(rules.action_for("txt"), Some(RuleAction::Rename));
+        assert_eq!(rules.action_for("md"), Some(RuleAction::Rename));
+        assert_eq!(rules.action_for("DAT"), Some(RuleAction::Report));
+        assert_eq!(rules.action_for("bin"), Some(RuleAction::Skip));
+
+        let rules = parsed("dat = report\n").unwrap();
+        assert_eq!(rules.action_for("bin"), None);
+    }
+
+    #[test]
+    fn extension_rules_are_checked() {
+        for contents in [
+            "txt rename\n",
+            "txt = delete\n",
+            " = report\n",
+            "txt = rename\nTXT = report\n",
+            "* = rename\n* = skip\n",
+        ] {
+            assert!(parsed(contents).is_err(), "{contents:?}");
+        }
+    }
+
+    #[test]
+    fn extension_sets_take_any_separator() {
+        let dir = TempDir::new();
+        let path = dir.file("allowed", b"# images\njpg, .PNG\n\ngif webp\n");
+        let set = parse_extension_set(path.to_str().unwrap()).unwrap();
+        for extension in ["jpg", "png", "GIF", "webp"] {
+            assert!(set.contains(extension), "{extension}");
+        }
+        assert!(!set.contains("images"));
+        assert!(!set.contains("pdf"));
+
+        let path = dir.file("empty", b"# nothing yet\n");
+        assert!(parse_extension_set(path.to_str().unwrap()).is_err());
+    }
+}
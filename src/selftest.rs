@@ -0,0 +1,141 @@
+use crate::Kti;
+use crate::detect::get_correct_extension;
+use clap::Parser;
+use std::error::Error;
+use std::fs;
+
+// The smallest headers each format is recognized by, named after the
+// extension kti should detect. Formats only found with flags like --deep are
+// left out, as the samples are checked with the defaults.
+const SAMPLES: [(&str, &[u8]); 77] = [
+    ("7z", include_bytes!("../samples/sample.7z")),
+    ("a", include_bytes!("../samples/sample.a")),
+    ("aiff", include_bytes!("../samples/sample.aiff")),
+    ("asc", include_bytes!("../samples/sample.asc")),
+    ("asf", include_bytes!("../samples/sample.asf")),
+    ("bc", include_bytes!("../samples/sample.bc")),
+    ("beam", include_bytes!("../samples/sample.beam")),
+    ("blend", include_bytes!("../samples/sample.blend")),
+    ("cab", include_bytes!("../samples/sample.cab")),
+    ("chm", include_bytes!("../samples/sample.chm")),
+    ("class", include_bytes!("../samples/sample.class")),
+    ("cpio", include_bytes!("../samples/sample.cpio")),
+    ("crx", include_bytes!("../samples/sample.crx")),
+    ("dcm", include_bytes!("../samples/sample.dcm")),
+    ("deb", include_bytes!("../samples/sample.deb")),
+    ("dex", include_bytes!("../samples/sample.dex")),
+    ("dwg", include_bytes!("../samples/sample.dwg")),
+    ("dxbc", include_bytes!("../samples/sample.dxbc")),
+    ("epub", include_bytes!("../samples/sample.epub")),
+    ("exr", include_bytes!("../samples/sample.exr")),
+    ("fb2", include_bytes!("../samples/sample.fb2")),
+    ("flac", include_bytes!("../samples/sample.flac")),
+    ("flv", include_bytes!("../samples/sample.flv")),
+    ("gif", include_bytes!("../samples/sample.gif")),
+    ("hdr", include_bytes!("../samples/sample.hdr")),
+    ("hlp", include_bytes!("../samples/sample.hlp")),
+    ("ics", include_bytes!("../samples/sample.ics")),
+    ("idx", include_bytes!("../samples/sample.idx")),
+    ("jar", include_bytes!("../samples/sample.jar")),
+    ("jks", include_bytes!("../samples/sample.jks")),
+    ("jpg", include_bytes!("../samples/sample.jpg")),
+    ("luac", include_bytes!("../samples/sample.luac")),
+    ("macho", include_bytes!("../samples/sample.macho")),
+    ("mar", include_bytes!("../samples/sample.mar")),
+    ("mkv", include_bytes!("../samples/sample.mkv")),
+    ("mobi", include_bytes!("../samples/sample.mobi")),
+    ("mov", include_bytes!("../samples/sample.mov")),
+    ("mp3", include_bytes!("../samples/sample.mp3")),
+    ("mp4", include_bytes!("../samples/sample.mp4")),
+    ("odex", include_bytes!("../samples/sample.odex")),
+    ("ogg", include_bytes!("../samples/sample.ogg")),
+    ("pack", include_bytes!("../samples/sample.pack")),
+    ("pbix", include_bytes!("../samples/sample.pbix")),
+    ("pcap", include_bytes!("../samples/sample.pcap")),
+    ("pcapng", include_bytes!("../samples/sample.pcapng")),
+    ("pdf", include_bytes!("../samples/sample.pdf")),
+    ("pdn", include_bytes!("../samples/sample.pdn")),
+    ("pem", include_bytes!("../samples/sample.pem")),
+    ("ply", include_bytes!("../samples/sample.ply")),
+    ("png", include_bytes!("../samples/sample.png")),
+    ("pyc", include_bytes!("../samples/sample.pyc")),
+    ("ra", include_bytes!("../samples/sample.ra")),
+    ("rm", include_bytes!("../samples/sample.rm")),
+    ("rpm", include_bytes!("../samples/sample.rpm")),
+    ("skp", include_bytes!("../samples/sample.skp")),
+    ("spv", include_bytes!("../samples/sample.spv")),
+    ("spv", include_bytes!("../samples/sample-be.spv")),
+    ("srt", include_bytes!("../samples/sample.srt")),
+    ("stl", include_bytes!("../samples/sample.stl")),
+    ("svg", include_bytes!("../samples/sample.svg")),
+    ("swf", include_bytes!("../samples/sample.swf")),
+    ("tar", include_bytes!("../samples/sample.tar")),
+    ("tif", include_bytes!("../samples/sample.tif")),
+    ("torrent", include_bytes!("../samples/sample.torrent")),
+    ("twbx", include_bytes!("../samples/sample.twbx")),
+    ("vcf", include_bytes!("../samples/sample.vcf")),
+    ("vtt", include_bytes!("../samples/sample.vtt")),
+    ("wasm", include_bytes!("../samples/sample.wasm")),
+    ("wat", include_bytes!("../samples/sample.wat")),
+    ("wav", include_bytes!("../samples/sample.wav")),
+    ("webm", include_bytes!("../samples/sample.webm")),
+    ("webp", include_bytes!("../samples/sample.webp")),
+    ("xar", include_bytes!("../samples/sample.xar")),
+    ("xcf", include_bytes!("../samples/sample.xcf")),
+    ("xz", include_bytes!("../samples/sample.xz")),
+    ("zip", include_bytes!("../samples/sample.zip")),
+    ("zst", include_bytes!("../samples/sample.zst")),
+];
+
+/// Checks the detector against the samples built into kti for `kti selftest`.
+/// Each sample is written to a temporary file, as detection works on files.
+/// Returns whether every format was detected as expected.
+pub fn run(verbose: bool) -> Result<bool, Box<dyn Error>> {
+    // The samples are meant for the default flags, whatever else was given.
+    let kti = Kti::parse_from(["kti"]);
+    let dir = std::env::temp_dir().join(format!("kti-selftest-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    let sample = dir.join("sample");
+
+    let mut failed = 0;
+    for (extension, bytes) in SAMPLES {
+        fs::write(&sample, bytes)?;
+        match get_correct_extension(&sample, &kti) {
+            Ok(Some(detection)) if detection.extension == extension => {
+                if verbose {
+                    println!("ok    {extension}");
+                }
+            }
+            Ok(Some(detection)) => {
+                println!("FAIL  {extension}: detected as {}", detection.extension);
+                failed += 1;
+            }
+            Ok(None) => {
+                println!("FAIL  {extension}: not detected");
+                failed += 1;
+            }
+            Err(e) => {
+                println!("FAIL  {extension}: {e}");
+                failed += 1;
+            }
+        }
+    }
+    let _ = fs::remove_dir_all(&dir);
+
+    println!(
+        "Self-test: {} formats checked, {} failed",
+        SAMPLES.len(),
+        failed
+    );
+    Ok(failed == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_sample_is_detected() {
+        assert!(run(false).unwrap());
+    }
+}
@@ -0,0 +1,54 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Files that timed out with --read-timeout in earlier runs, kept in the
+/// --detect-timeout-skip-list file with one absolute path per line.
+#[derive(Debug, Clone)]
+pub struct SkipList {
+    file: PathBuf,
+    paths: BTreeSet<PathBuf>,
+}
+
+impl SkipList {
+    /// Whether an earlier run listed `path` as too slow to read.
+    pub fn contains(&self, path: &Path) -> bool {
+        std::path::absolute(path).is_ok_and(|path| self.paths.contains(&path))
+    }
+
+    /// Writes the list back with the files that timed out in this run added
+    /// and the ones that could be read again, with --retry-skipped, removed.
+    pub fn save(&self, timed_out: &[PathBuf], recovered: &[PathBuf]) -> io::Result<()> {
+        let mut paths = self.paths.clone();
+        for path in recovered {
+            paths.remove(&std::path::absolute(path)?);
+        }
+        for path in timed_out {
+            paths.insert(std::path::absolute(path)?);
+        }
+        let contents: String = paths
+            .iter()
+            .map(|path| format!("{}\n", path.display()))
+            .collect();
+        fs::write(&self.file, contents)
+    }
+}
+
+/// Reads a skip list for --detect-timeout-skip-list. A list that doesn't
+/// exist yet is empty and is created at the end of the run.
+pub fn parse_skip_list(value: &str) -> Result<SkipList, String> {
+    let contents = match fs::read_to_string(value) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("could not read '{value}': {e}")),
+    };
+    Ok(SkipList {
+        file: PathBuf::from(value),
+        paths: contents
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(PathBuf::from)
+            .collect(),
+    })
+}
@@ -0,0 +1,90 @@
+const TOKENS: [&str; 6] = ["path", "name", "current", "detected", "changed", "stem"];
+
+/// A per-file report format given with --report-template, parsed up front so
+/// unknown tokens are rejected before any file is checked.
+#[derive(Debug, Clone)]
+pub struct ReportTemplate {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Text(String),
+    Token(&'static str),
+}
+
+/// The values a template can refer to for a single file. They are owned, as
+/// --two-pass keeps them until it knows whether the file was renamed.
+#[derive(Debug)]
+pub struct TemplateValues {
+    pub path: String,
+    pub name: String,
+    pub current: String,
+    pub detected: String,
+    pub changed: bool,
+    pub stem: String,
+}
+
+impl ReportTemplate {
+    pub fn render(&self, values: &TemplateValues) -> String {
+        let mut output = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => output.push_str(text),
+                Part::Token("path") => output.push_str(&values.path),
+                Part::Token("name") => output.push_str(&values.name),
+                Part::Token("current") => output.push_str(&values.current),
+                Part::Token("detected") => output.push_str(&values.detected),
+                Part::Token("changed") => output.push_str(&values.changed.to_string()),
+                Part::Token("stem") => output.push_str(&values.stem),
+                Part::Token(_) => {}
+            }
+        }
+        output
+    }
+}
+
+/// Parses a template such as `{path}\t{detected}\n`. The escapes `\t`, `\n`
+/// and `\\` are turned into a tab, a newline and a backslash.
+pub fn parse_template(value: &str) -> Result<ReportTemplate, String> {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('t') => text.push('\t'),
+                Some('n') => text.push('\n'),
+                Some('\\') => text.push('\\'),
+                Some(other) => return Err(format!("unknown escape '\\{other}'")),
+                None => return Err("template ends with a lone '\\'".to_string()),
+            },
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => return Err(format!("unclosed token '{{{name}'")),
+                    }
+                }
+                let Some(token) = TOKENS.iter().find(|token| **token == name) else {
+                    return Err(format!(
+                        "unknown token '{{{name}}}', expected one of {}",
+                        TOKENS.map(|token| format!("{{{token}}}")).join(", ")
+                    ));
+                };
+                if !text.is_empty() {
+                    parts.push(Part::Text(std::mem::take(&mut text)));
+                }
+                parts.push(Part::Token(token));
+            }
+            c => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        parts.push(Part::Text(text));
+    }
+    Ok(ReportTemplate { parts })
+}
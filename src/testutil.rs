@@ -0,0 +1,134 @@
+use crate::detect::{Detection, get_correct_extension};
+use crate::{Kti, Run};
+use clap::Parser;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// A directory of its own below the temp directory for a test, removed again
+/// when it goes out of scope.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> TempDir {
+        let path = std::env::temp_dir().join(format!(
+            "kti-test-{}-{}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes `contents` to `name` in the directory, creating the
+    /// directories leading to it.
+    pub fn file(&self, name: &str, contents: &[u8]) -> PathBuf {
+        let path = self.path.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Parses `args` as given after `kti` on the command line.
+pub fn kti(args: &[&str]) -> Kti {
+    Kti::try_parse_from(std::iter::once("kti").chain(args.iter().copied())).unwrap()
+}
+
+/// Runs kti with `args` over `dir` the way the command line does, up to the
+/// summary it would print.
+pub fn scan(dir: &Path, args: &[&str]) -> Run {
+    let mut kti = kti(&[args, &[dir.to_str().unwrap()]].concat());
+    crate::imply_options(&mut kti);
+    crate::scan(dir, &kti)
+}
+
+/// Detects a file holding `contents` the way kti run with `args` would.
+pub fn detection(contents: &[u8], args: &[&str]) -> Option<Detection> {
+    let dir = TempDir::new();
+    let path = dir.file("file", contents);
+    get_correct_extension(&path, &kti(args)).unwrap()
+}
+
+/// The extension detected for a file holding `contents`, if any.
+pub fn detected(contents: &[u8], args: &[&str]) -> Option<String> {
+    detection(contents, args).map(|detection| detection.extension)
+}
+
+/// A zip archive holding `members` uncompressed. The CRCs are left zero, as
+/// kti doesn't check them.
+pub fn zip_archive(members: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut archive = Vec::new();
+    let mut directory = Vec::new();
+    for (name, data) in members {
+        let offset = archive.len() as u32;
+        let size = (data.len() as u32).to_le_bytes();
+        let name_length = (name.len() as u16).to_le_bytes();
+
+        archive.extend_from_slice(b"PK\x03\x04\x14\0\0\0\0\0\0\0\0\0\0\0\0\0");
+        archive.extend_from_slice(&size);
+        archive.extend_from_slice(&size);
+        archive.extend_from_slice(&name_length);
+        archive.extend_from_slice(&[0, 0]);
+        archive.extend_from_slice(name.as_bytes());
+        archive.extend_from_slice(data);
+
+        directory.extend_from_slice(b"PK\x01\x02\x14\0\x14\0\0\0\0\0\0\0\0\0\0\0\0\0");
+        directory.extend_from_slice(&size);
+        directory.extend_from_slice(&size);
+        directory.extend_from_slice(&name_length);
+        directory.extend_from_slice(&[0; 12]);
+        directory.extend_from_slice(&offset.to_le_bytes());
+        directory.extend_from_slice(name.as_bytes());
+    }
+    let directory_offset = archive.len() as u32;
+    let count = (members.len() as u16).to_le_bytes();
+    archive.extend_from_slice(&directory);
+    archive.extend_from_slice(b"PK\x05\x06\0\0\0\0");
+    archive.extend_from_slice(&count);
+    archive.extend_from_slice(&count);
+    archive.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+    archive.extend_from_slice(&directory_offset.to_le_bytes());
+    archive.extend_from_slice(&[0, 0]);
+    archive
+}
+
+/// A ustar archive holding `members` as regular files.
+pub fn tar_archive(members: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut archive = Vec::new();
+    for (name, data) in members {
+        let mut header = [0; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..108].copy_from_slice(b"0000644\0");
+        header[124..136].copy_from_slice(format!("{:011o}\0", data.len()).as_bytes());
+        header[136..148].copy_from_slice(b"00000000000\0");
+        header[156] = b'0';
+        header[257..265].copy_from_slice(b"ustar\x0000");
+        // The checksum is taken with its own field filled with spaces.
+        header[148..156].fill(b' ');
+        let checksum: u32 = header.iter().map(|byte| *byte as u32).sum();
+        header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().div_ceil(512) * 512, 0);
+    }
+    archive.resize(archive.len() + 1024, 0);
+    archive
+}
@@ -0,0 +1,93 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// The name of the report --dump-unknown writes into its directory.
+pub const REPORT_NAME: &str = "unknown-signatures.txt";
+
+/// Undetected files grouped by their first bytes for --dump-unknown.
+#[derive(Debug, Default)]
+pub struct UnknownFiles {
+    /// Hex prefix -> display paths of the files starting with it.
+    groups: BTreeMap<String, Vec<String>>,
+}
+
+impl UnknownFiles {
+    /// Adds a file that wasn't detected under the hex of its first `len` bytes.
+    /// Empty files have nothing to report and are left out.
+    pub fn add(&mut self, path: &Path, display: String, len: usize) -> io::Result<()> {
+        let mut head = Vec::new();
+        fs::File::open(path)?
+            .take(len as u64)
+            .read_to_end(&mut head)?;
+        if head.is_empty() {
+            return Ok(());
+        }
+        let prefix = head
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        self.groups.entry(prefix).or_default().push(display);
+        Ok(())
+    }
+
+    /// Writes the groups to `dir`, the prefixes shared by the most files first.
+    pub fn write_report(&self, dir: &Path) -> io::Result<()> {
+        let mut groups: Vec<(&String, &Vec<String>)> = self.groups.iter().collect();
+        groups.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then(a.0.cmp(b.0)));
+
+        let mut report = String::new();
+        for (prefix, files) in groups {
+            let noun = if files.len() == 1 { "file" } else { "files" };
+            report.push_str(&format!("{prefix}  ({} {noun})\n", files.len()));
+            for file in files {
+                report.push_str(&format!("  {file}\n"));
+            }
+            report.push('\n');
+        }
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join(REPORT_NAME), report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn report(unknown: &UnknownFiles) -> String {
+        let dir = TempDir::new();
+        unknown.write_report(dir.path()).unwrap();
+        fs::read_to_string(dir.path().join(REPORT_NAME)).unwrap()
+    }
+
+    #[test]
+    fn files_starting_alike_are_grouped_most_common_first() {
+        let dir = TempDir::new();
+        let mut unknown = UnknownFiles::default();
+        for (name, contents) in [
+            ("a", &b"\x01\x02\x03\x04 rest of a"[..]),
+            ("b", b"\xFF\xFE"),
+            ("c", b"\x01\x02\x03\x04 rest of c"),
+        ] {
+            let path = dir.file(name, contents);
+            unknown.add(&path, name.to_string(), 4).unwrap();
+        }
+        assert_eq!(
+            report(&unknown),
+            "01 02 03 04  (2 files)\n  a\n  c\n\nff fe  (1 file)\n  b\n\n"
+        );
+    }
+
+    #[test]
+    fn empty_files_are_left_out() {
+        let dir = TempDir::new();
+        let mut unknown = UnknownFiles::default();
+        unknown
+            .add(&dir.file("empty", b""), "empty".to_string(), 16)
+            .unwrap();
+        assert_eq!(report(&unknown), "");
+    }
+}
@@ -0,0 +1,87 @@
+use crate::{Kti, Run, check_file};
+use notify::event::ModifyKind;
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+// How long a file's size has to stay the same before it is treated as fully
+// written. Checking a file that is still being downloaded would misdetect it.
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+pub fn watch(dir: &Path, kti: &Kti) -> Result<(), Box<dyn Error>> {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    watcher.watch(dir, RecursiveMode::Recursive)?;
+    eprintln!("Watching {} for new files", dir.display());
+
+    // Path -> (last seen size, when that size was first seen)
+    let mut pending: HashMap<PathBuf, (Option<u64>, Instant)> = HashMap::new();
+    let mut run = Run::default();
+
+    loop {
+        match receiver.recv_timeout(POLL_INTERVAL) {
+            Ok(Ok(event)) => {
+                if matches!(
+                    event.kind,
+                    EventKind::Create(_)
+                        | EventKind::Modify(ModifyKind::Name(_))
+                        | EventKind::Modify(ModifyKind::Data(_))
+                ) {
+                    for path in event.paths {
+                        if kti.show_hidden || !is_hidden_path(&path) {
+                            pending.insert(path, (None, Instant::now()));
+                        }
+                    }
+                }
+            }
+            Ok(Err(e)) => eprintln!("Error watching directory: {}", e),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        let now = Instant::now();
+        let mut ready = Vec::new();
+        pending.retain(|path, (size, since)| {
+            let Ok(metadata) = fs::metadata(path) else {
+                return false;
+            };
+            if !metadata.is_file() {
+                return false;
+            }
+            if *size != Some(metadata.len()) {
+                *size = Some(metadata.len());
+                *since = now;
+                return true;
+            }
+            if now.duration_since(*since) < SETTLE_TIME {
+                return true;
+            }
+            ready.push(path.clone());
+            false
+        });
+
+        for path in ready {
+            let depth = path
+                .strip_prefix(dir)
+                .map(|relative| relative.components().count())
+                .unwrap_or(0);
+            check_file(&path, depth, dir, kti, &mut run);
+            if run.aborted {
+                std::process::exit(1);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn is_hidden_path(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.starts_with("."))
+        .unwrap_or(false)
+}